@@ -1,19 +1,31 @@
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use indicatif::{ProgressBar, ProgressStyle};
 use noodles_vcf::{self as vcf};
-use rust_htslib::faidx;
+use rust_htslib::{bam, faidx};
 use std::{
+    collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
 };
 
 use crate::kplib::{
-    build_region_tree, BamParser, GTArgs, GenotypeAnno, IOParams, PathScore, Ploidy, PloidyRegions,
-    PlupParser, ReadParser, Variants, VcfChunker, VcfWriter,
+    build_region_tree, downsample_haplotypes, haplotag_records, load_af_models, write_af_models,
+    AfFitter, AfModel, BamParser, EvidenceWriter, GTArgs, HaplotagWriter, IOParams, KdpVcf,
+    MultiParser, PathScore, Ploidy, PloidyRegions, PlupParser, ReadParser, TrCatalog, VariantAnno,
+    Variants, VcfChunker, VcfWriter,
 };
 
 type InputType = Option<Vec<vcf::variant::RecordBuf>>;
-type OutputType = Option<Vec<GenotypeAnno>>;
+type OutputType = Option<Vec<VariantAnno>>;
+
+/// Channels the task threads send their results to
+#[derive(Clone)]
+struct TaskSenders {
+    results: Sender<OutputType>,
+    // Tagged spanning reads of each neighborhood for --haplotag
+    haplotag: Option<Sender<Vec<bam::Record>>>,
+}
 
 fn hp_sorter(a: &Option<u8>, b: &Option<u8>) -> std::cmp::Ordering {
     match (a, b) {
@@ -40,9 +52,14 @@ fn write_thread(
     result_receiver: Receiver<OutputType>,
     wt_io: IOParams,
     wt_header: vcf::Header,
+    wt_samples: Vec<String>,
     wt_num_variants: Arc<Mutex<u64>>,
 ) {
-    let mut m_writer = VcfWriter::new(&wt_io.out, wt_header.clone(), &wt_io.sample);
+    let mut m_writer = VcfWriter::new(&wt_io.out, wt_header.clone(), &wt_samples);
+    let mut m_evidence = wt_io
+        .evidence
+        .as_ref()
+        .map(|path| EvidenceWriter::new(path, &wt_samples));
 
     let mut pbar: Option<ProgressBar> = None;
     let sty =
@@ -61,6 +78,9 @@ fn write_thread(
             Ok(Some(result)) => {
                 let mut rsize: u64 = 0;
                 for entry in result {
+                    if let Some(ref mut evidence) = m_evidence {
+                        evidence.write(&entry);
+                    }
                     m_writer.anno_write(entry);
                     rsize += 1;
                 }
@@ -86,71 +106,262 @@ fn write_thread(
     info!("genotype counts: {:#?}", m_writer.gtcounts);
 }
 
+/// Collects the tagged reads of each neighborhood until every task thread is finished, then
+/// writes them sorted
+fn haplotag_thread(receiver: Receiver<Vec<bam::Record>>, args: GTArgs, reads: PathBuf) {
+    let out = args.io.haplotag.expect("--haplotag output");
+    let mut m_writer = HaplotagWriter::new(&out, &reads, &args.io.reference);
+    while let Ok(records) = receiver.recv() {
+        m_writer.write(records);
+    }
+    m_writer.finish();
+}
+
+/// Collects each sample's confident genotypes to fit their allele-fraction models
+fn fit_thread(result_receiver: Receiver<OutputType>, n_samples: usize) -> Vec<AfModel> {
+    let mut fitters = vec![AfFitter::default(); n_samples];
+    while let Ok(Some(result)) = result_receiver.recv() {
+        for entry in result {
+            for (fitter, anno) in fitters.iter_mut().zip(&entry.samples) {
+                fitter.add(anno);
+            }
+        }
+    }
+    fitters.iter().map(|fitter| fitter.fit()).collect()
+}
+
+/// Opens the parser for a sample's reads, combining them when the sample has multiple files
+fn open_sample_reads(reads: Vec<PathBuf>, m_args: &GTArgs) -> Box<dyn ReadParser> {
+    let mut parsers: Vec<Box<dyn ReadParser>> = reads
+        .into_iter()
+        .map(|m_reads| open_reads(m_reads, m_args))
+        .collect();
+    if parsers.len() == 1 {
+        parsers.pop().unwrap()
+    } else {
+        Box::new(MultiParser::new(parsers))
+    }
+}
+
+/// Opens the parser for a reads file (.plup.gz or otherwise .bam/.cram)
+fn open_reads(reads: PathBuf, m_args: &GTArgs) -> Box<dyn ReadParser> {
+    let reference = faidx::Reader::from_path(&m_args.io.reference).unwrap();
+    // Reference reads' names are reported, tagged or hashed for downsampling
+    let keep_ref_reads =
+        m_args.io.evidence.is_some() || m_args.io.haplotag.is_some() || m_args.kd.max_coverage > 0;
+    match reads.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.ends_with(".plup.gz") => Box::new(PlupParser::new(
+            reads,
+            reference,
+            m_args.kd.clone(),
+            keep_ref_reads,
+        )),
+        _ => Box::new(BamParser::new(
+            reads,
+            m_args.io.reference.clone(),
+            reference,
+            m_args.kd.clone(),
+            m_args.io.haplotag.is_some(),
+            keep_ref_reads,
+        )),
+    }
+}
+
 fn task_thread(
     m_args: GTArgs,
+    m_reads: Vec<Vec<PathBuf>>,
     m_receiver: Receiver<InputType>,
-    m_result_sender: Sender<OutputType>,
+    m_senders: TaskSenders,
     m_ploidy: PloidyRegions,
+    m_tr_catalog: TrCatalog,
+    m_models: Vec<AfModel>,
 ) {
     let reference = faidx::Reader::from_path(&m_args.io.reference).unwrap();
-    let mut m_reads: Box<dyn ReadParser> =
-        match m_args.io.reads.file_name().and_then(|name| name.to_str()) {
-            Some(name) if name.ends_with(".plup.gz") => Box::new(PlupParser::new(
-                m_args.io.reads,
-                reference,
-                m_args.kd.clone(),
-            )),
-            _ => Box::new(BamParser::new(
-                m_args.io.reads,
-                m_args.io.reference,
-                reference,
-                m_args.kd.clone(),
-            )),
-        };
+    let mut m_reads: Vec<Box<dyn ReadParser>> = m_reads
+        .into_iter()
+        .map(|reads| open_sample_reads(reads, &m_args))
+        .collect();
 
     loop {
         match m_receiver.recv() {
             Ok(None) | Err(_) => break,
             Ok(Some(chunk)) => {
-                let mut m_graph = Variants::new(chunk, m_args.kd.kmer, m_args.kd.maxhom);
+                let mut m_graph =
+                    Variants::new(chunk, &reference, m_args.kd.kmer, m_args.kd.maxhom);
 
                 let ploidy = m_ploidy.get_ploidy(&m_graph.chrom, m_graph.start);
                 // For zero, we don't have to waste time going into the bam
                 if ploidy == Ploidy::Zero {
-                    m_result_sender
-                        .send(Some(m_graph.take_annotated(&[], 0, &ploidy)))
+                    let samples = vec![(vec![], 0, vec![]); m_reads.len()];
+                    m_senders
+                        .results
+                        .send(Some(m_graph.take_annotated(&samples, &ploidy, &m_models)))
                         .unwrap();
                     continue;
                 }
 
-                let (haps, coverage) =
-                    m_reads.find_pileups(&m_graph.chrom, m_graph.start, m_graph.end);
-                let haps = ploidy.cluster(haps, coverage, &m_args.kd);
+                let (window_start, window_end) = m_graph.pileup_window(&m_tr_catalog, &m_args.kd);
+                let breakpoints = if m_args.kd.clips {
+                    m_graph.ins_breakpoints()
+                } else {
+                    vec![]
+                };
+                let sample_haps: Vec<_> = m_reads
+                    .iter_mut()
+                    .zip(&m_models)
+                    .map(|(reads, model)| {
+                        let (haps, depth) = reads.find_pileups(
+                            &m_graph.chrom,
+                            window_start,
+                            window_end,
+                            &breakpoints,
+                        );
+                        // Partially spanning reads aren't clustered, they're applied individually
+                        let (partials, haps): (Vec<_>, Vec<_>) =
+                            haps.into_iter().partition(|h| h.span.is_some());
+                        let kept = downsample_haplotypes(
+                            haps,
+                            partials,
+                            reads.take_ref_reads(),
+                            depth,
+                            m_args.kd.max_coverage,
+                        );
+                        // Every spanning read, so those left off of the paths can be reported
+                        let mut spanning = kept.ref_reads;
+                        // Reference reads are reported by --evidence and tagged by --haplotag
+                        if m_args.io.evidence.is_some() || m_args.io.haplotag.is_some() {
+                            spanning.extend(kept.haps.iter().flat_map(|h| h.reads.iter().cloned()));
+                        } else {
+                            spanning.clear();
+                        }
+                        (
+                            ploidy.cluster(kept.haps, kept.coverage, &m_args.kd, model),
+                            kept.coverage,
+                            kept.partials,
+                            (kept.dropped, kept.dropped_partials),
+                            spanning,
+                        )
+                    })
+                    .collect();
+                let dropped: Vec<_> = sample_haps.iter().map(|s| s.3.clone()).collect();
 
                 // Only need to build the full graph sometimes
-                let should_build = !haps.is_empty()
+                let should_build = sample_haps
+                    .iter()
+                    .any(|(haps, _, partials, _, _)| !haps.is_empty() || !partials.is_empty())
                     && !m_args.kd.one_to_one
                     && m_graph.node_indices.len() <= (m_args.kd.maxnodes + 2);
                 m_graph.build(should_build);
 
-                let mut paths: Vec<PathScore> = haps
-                    .iter()
-                    .map(|h| m_graph.apply_coverage(h, &m_args.kd))
-                    .filter(|p| *p != PathScore::default())
-                    .collect();
-                paths.sort_by(|a, b| hp_sorter(&a.hp, &b.hp));
+                let (samples, ref_reads): (Vec<_>, Vec<_>) = sample_haps
+                    .into_iter()
+                    .map(|(haps, coverage, partials, _, spanning)| {
+                        let mut paths: Vec<PathScore> = haps
+                            .iter()
+                            .map(|h| m_graph.apply_coverage(h, &m_args.kd))
+                            .filter(|p| *p != PathScore::default())
+                            .collect();
+                        // Sort paths based on their HP if set
+                        paths.sort_by(|a, b| hp_sorter(&a.hp, &b.hp));
+                        let partials = partials
+                            .iter()
+                            .map(|h| m_graph.apply_coverage(h, &m_args.kd))
+                            .collect();
+                        let ref_reads = unused_reads(spanning, &paths);
+                        ((paths, coverage, partials), ref_reads)
+                    })
+                    .unzip();
+
+                let mut annotated = m_graph.take_annotated(&samples, &ploidy, &m_models);
+
+                // --haplotag is only allowed with a single sample
+                if let Some(sender) = &m_senders.haplotag {
+                    let mut records = m_reads[0].take_records();
+                    haplotag_records(
+                        &mut records,
+                        &annotated,
+                        &samples[0].0,
+                        &ref_reads[0],
+                        m_graph.start,
+                    );
+                    sender.send(records).unwrap();
+                }
 
-                // Sort paths based on their HP if set
-                m_result_sender
-                    .send(Some(m_graph.take_annotated(&paths, coverage, &ploidy)))
-                    .unwrap();
+                for anno in annotated.iter_mut() {
+                    let (start, end) = anno.entry.boundaries();
+                    for ((sample, (spanning, partials)), reads) in
+                        anno.samples.iter_mut().zip(&dropped).zip(&ref_reads)
+                    {
+                        // Dropped partial reads only count toward the entries they cover
+                        let dropped = spanning
+                            + partials
+                                .iter()
+                                .filter(|(s, e)| *s <= start && end <= *e)
+                                .count() as u64;
+                        if dropped > 0 {
+                            sample.downsampled(dropped);
+                        }
+                        sample.ref_reads.clone_from(reads);
+                    }
+                }
+                m_senders.results.send(Some(annotated)).unwrap();
             }
         }
     }
     // This should give a result
 }
 
-pub fn genotype_main(args: GTArgs) {
+/// Reads which aren't on any of the paths. Reads are matched by name and unnamed reads by count
+fn unused_reads(mut reads: Vec<String>, paths: &[PathScore]) -> Vec<String> {
+    let mut used: HashMap<&str, usize> = HashMap::new();
+    for name in paths.iter().flat_map(|p| p.reads.iter()) {
+        *used.entry(name.as_str()).or_insert(0) += 1;
+    }
+    reads.retain(|name| match used.get_mut(name.as_str()) {
+        Some(cnt) if *cnt > 0 => {
+            *cnt -= 1;
+            false
+        }
+        _ => true,
+    });
+    reads
+}
+
+/// Output sample names and their reads.
+/// A single unnamed sample takes the --input's first sample name
+fn resolve_samples(io: &IOParams, header: &vcf::Header) -> (Vec<String>, Vec<Vec<PathBuf>>) {
+    io.sample_reads()
+        .into_iter()
+        .map(|(name, reads)| {
+            let name = name.unwrap_or_else(|| {
+                if header.sample_names().is_empty() {
+                    error!("--input contains no samples. --sample name must be provided");
+                    std::process::exit(1);
+                }
+                let samp_name = header.sample_names()[0].clone();
+                info!("Setting sample to {}", samp_name);
+                samp_name
+            });
+            (name, reads)
+        })
+        .unzip()
+}
+
+/// Genotypes the --input and hands the annotated variants to the `consumer` thread
+/// The consumer also gets the semaphore holding the number of variants once the input is read
+/// Tagged reads are sent to the `haplotag` channel when provided
+fn run_pass<T, F>(
+    args: &GTArgs,
+    sample_reads: &[Vec<PathBuf>],
+    ploidy: &PloidyRegions,
+    models: &[AfModel],
+    haplotag: Option<Sender<Vec<bam::Record>>>,
+    consumer: F,
+) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(Receiver<OutputType>, Arc<Mutex<u64>>) -> T + Send + 'static,
+{
     let mut input_vcf = vcf::io::reader::Builder::default()
         .build_from_path(args.io.input.clone())
         .expect("Unable to parse vcf");
@@ -158,8 +369,7 @@ pub fn genotype_main(args: GTArgs) {
 
     let m_contigs = input_header.contigs().clone();
     let tree = build_region_tree(&m_contigs, &args.io.bed);
-
-    let ploidy = PloidyRegions::new(&args.io.ploidy_bed);
+    let tr_catalog = TrCatalog::new(&args.io.tr_catalog);
 
     // Create channels for communication between threads
     let (task_sender, task_receiver): (Sender<InputType>, Receiver<InputType>) = unbounded();
@@ -169,35 +379,45 @@ pub fn genotype_main(args: GTArgs) {
     let task_handles: Vec<JoinHandle<()>> = (0..args.io.threads)
         .map(|_| {
             let m_args = args.clone();
+            let m_reads = sample_reads.to_vec();
             let m_receiver = task_receiver.clone();
-            let m_result_sender = result_sender.clone();
+            let m_senders = TaskSenders {
+                results: result_sender.clone(),
+                haplotag: haplotag.clone(),
+            };
             let m_ploidy = ploidy.clone();
+            let m_tr_catalog = tr_catalog.clone();
+            let m_models = models.to_vec();
 
             thread::spawn(move || {
-                task_thread(m_args, m_receiver, m_result_sender, m_ploidy);
+                task_thread(
+                    m_args,
+                    m_reads,
+                    m_receiver,
+                    m_senders,
+                    m_ploidy,
+                    m_tr_catalog,
+                    m_models,
+                );
             })
         })
         .collect();
 
-    // Before we start the workers, we'll start the writer
+    // Before we start the workers, we'll start the consumer
     // This is the semaphore for the progress bar that communicates between main and writer
     let num_variants = Arc::new(Mutex::new(0));
-
-    let wt_io = args.io.clone();
-    let wt_header = input_header.clone();
     let wt_num_variants = num_variants.clone();
-
-    let write_handler = thread::spawn(move || {
-        write_thread(result_receiver, wt_io, wt_header.clone(), wt_num_variants);
-    });
+    let consumer_handler = thread::spawn(move || consumer(result_receiver, wt_num_variants));
 
     info!("building variant graphs");
     let mut m_input = VcfChunker::new(
         input_vcf,
         input_header.clone(),
         tree,
+        tr_catalog,
         args.kd.clone(),
         result_sender.clone(),
+        sample_reads.len(),
     );
 
     // Send items to worker threads
@@ -229,7 +449,76 @@ pub fn genotype_main(args: GTArgs) {
     // There will be no more results made
     result_sender.send(None).unwrap();
 
-    // Wait on the writer
-    write_handler.join().unwrap();
+    // Wait on the consumer
+    consumer_handler.join().unwrap()
+}
+
+pub fn genotype_main(args: GTArgs) {
+    let mut input_vcf = vcf::io::reader::Builder::default()
+        .build_from_path(args.io.input.clone())
+        .expect("Unable to parse vcf");
+    let input_header = input_vcf.read_header().expect("Unable to parse vcf header");
+
+    let ploidy = PloidyRegions::new(&args.io.ploidy_bed, args.io.ploidy);
+
+    let (sample_names, sample_reads) = resolve_samples(&args.io, &input_header);
+    info!("genotyping {} samples", sample_names.len());
+
+    let n_samples = sample_names.len();
+    let models = match (&args.io.fit_af_model, &args.io.af_model) {
+        (Some(path), _) => {
+            info!("fitting allele-fraction models");
+            let defaults = vec![AfModel::default(); n_samples];
+            let models = run_pass(
+                &args,
+                &sample_reads,
+                &ploidy,
+                &defaults,
+                None,
+                move |recv, _| fit_thread(recv, n_samples),
+            );
+            write_af_models(path, &sample_names, &models);
+            models
+        }
+        (None, Some(path)) => load_af_models(path, &sample_names),
+        (None, None) => vec![AfModel::default(); n_samples],
+    };
+    for (name, model) in sample_names.iter().zip(&models) {
+        debug!("{} allele-fraction model {:?}", name, model);
+    }
+
+    // Reads are tagged during the final pass
+    let (haplotag_sender, haplotag_handle) = match args.io.haplotag {
+        Some(_) => {
+            let (sender, receiver) = unbounded();
+            let ht_args = args.clone();
+            let ht_reads = sample_reads[0][0].clone();
+            let handle = thread::spawn(move || haplotag_thread(receiver, ht_args, ht_reads));
+            (Some(sender), Some(handle))
+        }
+        None => (None, None),
+    };
+
+    let wt_io = args.io.clone();
+    let wt_samples = sample_names.clone();
+    run_pass(
+        &args,
+        &sample_reads,
+        &ploidy,
+        &models,
+        haplotag_sender,
+        move |result_receiver, wt_num_variants| {
+            write_thread(
+                result_receiver,
+                wt_io,
+                input_header,
+                wt_samples,
+                wt_num_variants,
+            )
+        },
+    );
+    if let Some(handle) = haplotag_handle {
+        handle.join().unwrap();
+    }
     info!("finished");
 }
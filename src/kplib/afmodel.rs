@@ -0,0 +1,119 @@
+use crate::kplib::{metrics::GTstate, GenotypeAnno};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+/// Coverage below which the low coverage allele-fractions are used
+const LOW_COVERAGE: f64 = 10.0;
+/// Minimum GQ of a genotype to be used for fitting
+const FIT_MIN_GQ: i32 = 20;
+/// Minimum number of genotypes to fit a state's allele-fraction
+const FIT_MIN_OBS: u64 = 20;
+/// Bounds of each state's fitted allele-fraction. Keeps genotypes separable
+const FIT_BOUNDS: [(f64, f64); 3] = [(1e-4, 0.15), (0.25, 0.75), (0.85, 0.999)];
+
+/// Expected alternate allele-fraction of reference, heterozygous, and homozygous genotypes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AfModel {
+    pub low_cov: [f64; 3],
+    pub high_cov: [f64; 3],
+}
+
+impl Default for AfModel {
+    fn default() -> Self {
+        Self {
+            // Needs to be more pure for lower coverage
+            low_cov: [1e-3, 0.55, 0.95],
+            high_cov: [1e-3, 0.50, 0.90],
+        }
+    }
+}
+
+impl AfModel {
+    /// Allele-fractions for a site's total coverage
+    pub fn p_alt(&self, total: f64) -> &[f64; 3] {
+        if total < LOW_COVERAGE {
+            &self.low_cov
+        } else {
+            &self.high_cov
+        }
+    }
+}
+
+/// Collects a sample's allele-fractions of confident genotypes to fit an `AfModel`
+#[derive(Default, Clone)]
+pub struct AfFitter {
+    // [low, high][ref, het, hom]
+    sums: [[f64; 3]; 2],
+    counts: [[u64; 3]; 2],
+}
+
+impl AfFitter {
+    /// Adds a genotype if it is a confident (unfiltered, GQ >= 20) biallelic call
+    pub fn add(&mut self, anno: &GenotypeAnno) {
+        if !anno.filt.is_empty() || anno.gq < FIT_MIN_GQ || anno.ad.len() != 2 {
+            return;
+        }
+        let state = match anno.gt_state {
+            GTstate::Ref => 0,
+            GTstate::Het => 1,
+            GTstate::Hom => 2,
+            GTstate::HetAlt | GTstate::Non => return,
+        };
+        let (ref_cov, alt_cov) = match (anno.ad[0], anno.ad[1]) {
+            (Some(r), Some(a)) => (r as f64, a as f64),
+            _ => return,
+        };
+        let total = ref_cov + alt_cov;
+        if total == 0.0 {
+            return;
+        }
+        let bin = (total >= LOW_COVERAGE) as usize;
+        self.sums[bin][state] += alt_cov / total;
+        self.counts[bin][state] += 1;
+    }
+
+    /// Mean allele-fraction of each state. States without enough genotypes keep the default
+    pub fn fit(&self) -> AfModel {
+        let default = AfModel::default();
+        let fit_bin = |bin: usize, dflt: &[f64; 3]| -> [f64; 3] {
+            let mut ret = *dflt;
+            for (state, value) in ret.iter_mut().enumerate() {
+                if self.counts[bin][state] >= FIT_MIN_OBS {
+                    let (lower, upper) = FIT_BOUNDS[state];
+                    *value = (self.sums[bin][state] / self.counts[bin][state] as f64)
+                        .clamp(lower, upper);
+                }
+            }
+            ret
+        };
+        AfModel {
+            low_cov: fit_bin(0, &default.low_cov),
+            high_cov: fit_bin(1, &default.high_cov),
+        }
+    }
+}
+
+/// Reads each sample's model from a JSON file of sample name -> `AfModel`.
+/// Samples missing from the file use the default model
+pub fn load_af_models(path: &Path, samples: &[String]) -> Vec<AfModel> {
+    let file = File::open(path).expect("Unable to open --af-model");
+    let models: BTreeMap<String, AfModel> =
+        serde_json::from_reader(BufReader::new(file)).expect("Unable to parse --af-model");
+    samples
+        .iter()
+        .map(|name| match models.get(name) {
+            Some(model) => *model,
+            None => {
+                warn!("--af-model has no model for {}. Using default", name);
+                AfModel::default()
+            }
+        })
+        .collect()
+}
+
+/// Writes each sample's model to a JSON file of sample name -> `AfModel`
+pub fn write_af_models(path: &Path, samples: &[String], models: &[AfModel]) {
+    let models: BTreeMap<&String, &AfModel> = samples.iter().zip(models).collect();
+    let file = File::create(path).expect("Unable to create --fit-af-model output");
+    serde_json::to_writer_pretty(file, &models).expect("Unable to write --fit-af-model output");
+}
@@ -1,4 +1,4 @@
-use crate::kplib::{metrics, PathScore, Ploidy};
+use crate::kplib::{metrics, AfModel, PathScore, Ploidy};
 use bitflags::bitflags;
 use noodles_vcf::{
     variant::record_buf::samples::sample::value::{Array, Value},
@@ -16,15 +16,34 @@ bitflags! {
         const LOWSQ      = 0b00001000;  // Sample quality below 5 (non-ref genotypes only)
         const LOWALT     = 0b00010000;  // Alt coverage below 5 (non-ref genotypes only)
         const PARTIAL    = 0b00100000;  // Best scoring path uses only part of the haplotype
+        const CLIPPED    = 0b01000000;  // Alternate allele's path uses soft-clipped evidence
+        const DOWNSAMPLED = 0b10000000; // Reads were downsampled to --max-coverage
     }
 }
 
 /// Format integer type number for genotype annotations.
 type IntG = Vec<Option<i32>>;
+/// Format float type number for genotype annotations.
+type FloatG = Vec<Option<f32>>;
 
-/// Struct representing genotype annotations.
-pub struct GenotypeAnno {
+/// A VCF entry and the genotype annotations of every sample.
+pub struct VariantAnno {
     pub entry: RecordBuf,
+    pub samples: Vec<GenotypeAnno>,
+}
+
+impl VariantAnno {
+    /// Creates a `VariantAnno` with missing genotypes for an entry which isn't analyzed.
+    pub fn skipped(entry: RecordBuf, n_samples: usize) -> Self {
+        let samples = (0..n_samples)
+            .map(|_| GenotypeAnno::new(&[], &[], 0, &Ploidy::Zero, 0, &AfModel::default()))
+            .collect();
+        Self { entry, samples }
+    }
+}
+
+/// Struct representing a sample's genotype annotations.
+pub struct GenotypeAnno {
     pub gt: String,
     pub filt: FiltFlags,
     pub sq: i32,
@@ -33,27 +52,41 @@ pub struct GenotypeAnno {
     pub dp: i32,
     pub ad: IntG,
     pub ks: IntG,
+    pub pl: IntG,
+    pub gl: FloatG,
     pub gt_state: metrics::GTstate,
     pub ne: u64,
+    pub hap_reads: Vec<(usize, Option<i32>, Vec<String>)>, // Allele, score and read names of each path
+    pub ref_reads: Vec<String>, // Spanning reads which aren't on any path (only set for --evidence)
 }
 
 impl GenotypeAnno {
     /// Creates a new `GenotypeAnno` instance based on the provided ploidy and parameters.
+    /// `alleles` holds the graph node of each of the entry's alternate alleles, in order. Alleles
+    /// which can't be genotyped have no node.
+    /// `model` holds the sample's expected allele-fractions of each genotype.
     pub fn new(
-        entry: RecordBuf,
-        var_idx: &NodeIndex,
+        alleles: &[Option<NodeIndex>],
         paths: &[PathScore],
         coverage: u64,
         ploidy: &Ploidy,
         neigh_group: u64,
+        model: &AfModel,
     ) -> Self {
         match ploidy {
-            Ploidy::Zero => zero(entry, coverage, neigh_group),
-            Ploidy::Haploid => haploid(entry, var_idx, paths, coverage, neigh_group),
-            _ => diploid(entry, var_idx, paths, coverage, neigh_group),
+            Ploidy::Zero => zero(alleles, coverage, neigh_group),
+            Ploidy::Haploid => haploid(alleles, paths, coverage, neigh_group, model),
+            Ploidy::Polyploid(n) => polyploid(alleles, paths, coverage, *n, neigh_group, model),
+            _ => diploid(alleles, paths, coverage, neigh_group, model),
         }
     }
 
+    /// Restores the true depth of a sample whose reads were downsampled
+    pub fn downsampled(&mut self, dropped: u64) {
+        self.dp += dropped as i32;
+        self.filt |= FiltFlags::DOWNSAMPLED;
+    }
+
     /// Generates fields for the `GenotypeAnno` to match `VcfWriter` keys.
     pub fn make_fields(&self) -> Vec<Option<Value>> {
         vec![
@@ -68,32 +101,161 @@ impl GenotypeAnno {
             Some(Value::Integer(self.dp)),
             Some(Value::Array(Array::Integer(self.ad.clone()))),
             Some(Value::Array(Array::Integer(self.ks.clone()))),
+            Some(Value::Array(Array::Integer(self.pl.clone()))),
+            Some(Value::Array(Array::Float(self.gl.clone()))),
         ]
     }
 }
 
+/// Allele number of the entry's node used by a path. Paths without the entry are reference (0)
+/// An entry's alleles overlap one another, so a path holds at most one of them.
+fn path_allele(alleles: &[Option<NodeIndex>], path: &PathScore) -> usize {
+    alleles
+        .iter()
+        .position(|idx| idx.is_some_and(|idx| path.path.contains(&idx)))
+        .map_or(0, |pos| pos + 1)
+}
+
+/// Adds the evidence of partially spanning reads covering an entry to its paths and coverage
+/// Reads supporting an allele add to the coverage of the path holding it. Otherwise, the allele
+/// gets its own path when the ploidy has room. All other reads are reference coverage
+pub fn add_partial_evidence(
+    alleles: &[Option<NodeIndex>],
+    paths: &[PathScore],
+    coverage: u64,
+    partials: &[&PathScore],
+    ploidy: &Ploidy,
+) -> (Vec<PathScore>, u64) {
+    let mut paths = paths.to_vec();
+    for partial in partials {
+        let allele = path_allele(alleles, partial);
+        if allele == 0 {
+            continue;
+        }
+        let cov = partial.coverage.unwrap_or(1);
+        if let Some(path) = paths.iter_mut().find(|p| path_allele(alleles, p) == allele) {
+            *path.coverage.get_or_insert(0) += cov;
+            path.reads.extend(partial.reads.iter().cloned());
+        } else if (paths.len() as u64) < ploidy.value() {
+            let mut path = (*partial).clone();
+            path.path = vec![alleles[allele - 1].expect("Paths only hold alleles with nodes")];
+            path.coverage = Some(cov);
+            path.span = None;
+            paths.push(path);
+        }
+    }
+    (paths, coverage + partials.len() as u64)
+}
+
 /// Helper function for a diploid region annotation.
 fn diploid(
-    entry: RecordBuf,
-    var_idx: &NodeIndex,
+    alleles: &[Option<NodeIndex>],
     paths: &[PathScore],
     coverage: u64,
     neigh_group: u64,
+    model: &AfModel,
 ) -> GenotypeAnno {
     let handle = match &paths {
-        [] => handle_diploid_no_paths(coverage),
-        [p] => handle_diploid_single_path(var_idx, p, coverage),
-        [p1, p2] => handle_diploid_two_paths(var_idx, p1, p2, coverage),
+        [] => handle_diploid_no_paths(alleles, coverage),
+        [p] => handle_diploid_single_path(alleles, p, coverage, model),
+        [p1, p2] => handle_diploid_two_paths(alleles, p1, p2, coverage),
         _ => panic!("Unexpected number of paths for diploid region"),
     };
 
-    finalize_annotation(entry, handle, paths, coverage, neigh_group)
+    finalize_annotation(
+        alleles,
+        handle,
+        paths,
+        coverage,
+        &Ploidy::Diploid,
+        neigh_group,
+        model,
+    )
+}
+
+/// Helper for a polyploid region annotation.
+/// Every path containing one of the variant's alleles contributes that allele's coverage and
+/// the dosage of each allele is determined from it. GTs are unphased.
+fn polyploid(
+    alleles: &[Option<NodeIndex>],
+    paths: &[PathScore],
+    coverage: u64,
+    ploidy: u8,
+    neigh_group: u64,
+    model: &AfModel,
+) -> GenotypeAnno {
+    let mut alt_covs = vec![0.0; alleles.len()];
+    let mut alt_full_target = false;
+    let mut any_alt = false;
+    for path in paths {
+        let allele = path_allele(alleles, path);
+        if allele != 0 {
+            alt_covs[allele - 1] += path.coverage.unwrap() as f64;
+            alt_full_target |= path.full_target;
+            any_alt = true;
+        }
+    }
+    // Only a called alternate allele can be a partial match of the target
+    let full_target = !any_alt || alt_full_target;
+
+    let (gt, gt_state) = if !any_alt {
+        match coverage {
+            0 => (polyploid_gt(None, ploidy), metrics::GTstate::Non),
+            _ => (polyploid_gt(Some(&[]), ploidy), metrics::GTstate::Ref),
+        }
+    } else {
+        // Highest covered alleles claim their copies first
+        let mut order: Vec<usize> = (0..alleles.len()).filter(|&i| alt_covs[i] > 0.0).collect();
+        order.sort_by(|&a, &b| alt_covs[b].total_cmp(&alt_covs[a]));
+
+        let mut remaining = ploidy;
+        let mut gt_alleles = vec![];
+        for i in order {
+            let alt_cov = alt_covs[i];
+            // Being on a path means at least one copy
+            let dosage =
+                metrics::dosage_genotyper(coverage as f64 - alt_cov, alt_cov, ploidy, model)
+                    .max(1)
+                    .min(remaining);
+            remaining -= dosage;
+            gt_alleles.extend(std::iter::repeat_n(i + 1, dosage as usize));
+        }
+        gt_alleles.sort();
+        // Every copy being an alternate allele, but not all the same one, is HetAlt
+        let state = if remaining == 0 && gt_alleles.first() != gt_alleles.last() {
+            metrics::GTstate::HetAlt
+        } else {
+            metrics::dosage_state(ploidy - remaining, ploidy)
+        };
+        (polyploid_gt(Some(&gt_alleles), ploidy), state)
+    };
+
+    finalize_annotation(
+        alleles,
+        (gt, gt_state, alt_covs, full_target),
+        paths,
+        coverage,
+        &Ploidy::Polyploid(ploidy),
+        neigh_group,
+        model,
+    )
+}
+
+/// Unphased GT string of the alternate alleles padded with reference alleles.
+/// No alleles is a missing genotype
+fn polyploid_gt(alt_alleles: Option<&[usize]>, ploidy: u8) -> String {
+    let alleles: Vec<String> = match alt_alleles {
+        Some(alts) => std::iter::repeat_n("0".to_string(), ploidy as usize - alts.len())
+            .chain(alts.iter().map(|a| a.to_string()))
+            .collect(),
+        None => vec![".".to_string(); ploidy as usize],
+    };
+    alleles.join("/")
 }
 
 /// Helper for zero ploidy regions.
-fn zero(entry: RecordBuf, coverage: u64, neigh_group: u64) -> GenotypeAnno {
+fn zero(alleles: &[Option<NodeIndex>], coverage: u64, neigh_group: u64) -> GenotypeAnno {
     GenotypeAnno {
-        entry,
         gt: "./.".to_string(),
         filt: FiltFlags::PASS,
         sq: 0,
@@ -101,136 +263,223 @@ fn zero(entry: RecordBuf, coverage: u64, neigh_group: u64) -> GenotypeAnno {
         ps: None,
         dp: coverage as i32,
         ad: vec![None],
-        ks: vec![None],
+        ks: vec![None; alleles.len().max(1)],
+        pl: vec![None],
+        gl: vec![None],
         gt_state: metrics::GTstate::Non,
         ne: neigh_group,
+        hap_reads: vec![],
+        ref_reads: vec![],
     }
 }
 
 /// Helper for haploid regions.
 /// Assumed to have ≤1 Path
 fn haploid(
-    entry: RecordBuf,
-    var_idx: &NodeIndex,
+    alleles: &[Option<NodeIndex>],
     paths: &[PathScore],
     coverage: u64,
     neigh_group: u64,
+    model: &AfModel,
 ) -> GenotypeAnno {
+    let mut alt_covs = vec![0.0; alleles.len()];
     if paths.is_empty() {
         let handle = match coverage {
-            0 => (".", metrics::GTstate::Non, 0.0, true),
-            _ => ("0", metrics::GTstate::Ref, 0.0, true),
+            0 => (".".to_string(), metrics::GTstate::Non, alt_covs, true),
+            _ => ("0".to_string(), metrics::GTstate::Ref, alt_covs, true),
         };
-        return finalize_annotation(entry, handle, paths, coverage, neigh_group);
+        return finalize_annotation(
+            alleles,
+            handle,
+            paths,
+            coverage,
+            &Ploidy::Haploid,
+            neigh_group,
+            model,
+        );
     }
 
     let path1 = &paths[0];
-    let handle = match path1.path.contains(var_idx) {
-        true => (
-            "1",
-            metrics::GTstate::Hom,
-            path1.coverage.unwrap_or(0) as f64,
-            true,
-        ),
-        false if coverage != 0 => ("0", metrics::GTstate::Ref, 0.0, true),
-        false => (".", metrics::GTstate::Non, 0.0, true),
+    let handle = match path_allele(alleles, path1) {
+        0 if coverage != 0 => ("0".to_string(), metrics::GTstate::Ref, alt_covs, true),
+        0 => (".".to_string(), metrics::GTstate::Non, alt_covs, true),
+        allele => {
+            alt_covs[allele - 1] = path1.coverage.unwrap_or(0) as f64;
+            (allele.to_string(), metrics::GTstate::Hom, alt_covs, true)
+        }
     };
-    finalize_annotation(entry, handle, paths, coverage, neigh_group)
+    finalize_annotation(
+        alleles,
+        handle,
+        paths,
+        coverage,
+        &Ploidy::Haploid,
+        neigh_group,
+        model,
+    )
 }
 
-/// GT str, GTstate, alt_cov, is_fulltarget
-type HandleReturn<'a> = (&'a str, metrics::GTstate, f64, bool);
+/// GT str, GTstate, alt_cov per allele, is_fulltarget
+type HandleReturn = (String, metrics::GTstate, Vec<f64>, bool);
 
-fn handle_diploid_no_paths<'a>(coverage: u64) -> HandleReturn<'a> {
+fn handle_diploid_no_paths(alleles: &[Option<NodeIndex>], coverage: u64) -> HandleReturn {
+    let alt_covs = vec![0.0; alleles.len()];
     if coverage != 0 {
-        ("0|0", metrics::GTstate::Ref, 0.0, true)
+        ("0|0".to_string(), metrics::GTstate::Ref, alt_covs, true)
     } else {
-        ("./.", metrics::GTstate::Non, 0.0, true)
+        ("./.".to_string(), metrics::GTstate::Non, alt_covs, true)
     }
 }
 
-fn handle_diploid_single_path<'a>(
-    var_idx: &NodeIndex,
+fn handle_diploid_single_path(
+    alleles: &[Option<NodeIndex>],
     path: &PathScore,
     coverage: u64,
-) -> HandleReturn<'a> {
-    if !path.path.contains(var_idx) {
-        ("0|0", metrics::GTstate::Ref, 0.0, true)
+    model: &AfModel,
+) -> HandleReturn {
+    let mut alt_covs = vec![0.0; alleles.len()];
+    let allele = path_allele(alleles, path);
+    if allele == 0 {
+        ("0|0".to_string(), metrics::GTstate::Ref, alt_covs, true)
     } else {
         let alt_cov = path.coverage.unwrap() as f64;
+        alt_covs[allele - 1] = alt_cov;
         let ref_cov = (coverage as f64) - alt_cov;
-        let (genotype, state) = match metrics::genotyper(ref_cov, alt_cov) {
+        let (genotype, state) = match metrics::genotyper(ref_cov, alt_cov, model) {
             metrics::GTstate::Ref | metrics::GTstate::Het => {
                 let gt = match path.hp {
-                    None => "0|1",
-                    Some(1) => "0|1",
-                    _ => "1|0",
+                    None => format!("0|{}", allele),
+                    Some(1) => format!("0|{}", allele),
+                    _ => format!("{}|0", allele),
                 };
                 (gt, metrics::GTstate::Het)
             }
-            metrics::GTstate::Hom => ("1|1", metrics::GTstate::Hom),
+            metrics::GTstate::Hom => (format!("{}|{}", allele, allele), metrics::GTstate::Hom),
             _ => panic!("Cannot happen here"),
         };
-        (genotype, state, alt_cov, path.full_target)
+        (genotype, state, alt_covs, path.full_target)
     }
 }
 
-fn handle_diploid_two_paths<'a>(
-    var_idx: &NodeIndex,
+/// Two different alternate alleles (e.g. 1|2) have no reference allele and are HetAlt
+fn handle_diploid_two_paths(
+    alleles: &[Option<NodeIndex>],
     path1: &PathScore,
     path2: &PathScore,
     coverage: u64,
-) -> HandleReturn<'a> {
-    match (path1.path.contains(var_idx), path2.path.contains(var_idx)) {
-        (true, true) => (
-            "1|1",
-            metrics::GTstate::Hom,
-            (path1.coverage.unwrap() + path2.coverage.unwrap()) as f64,
-            path1.full_target || path2.full_target,
-        ),
-        (true, false) => (
-            "1|0",
+) -> HandleReturn {
+    let mut alt_covs = vec![0.0; alleles.len()];
+    let allele1 = path_allele(alleles, path1);
+    let allele2 = path_allele(alleles, path2);
+    if allele1 != 0 {
+        alt_covs[allele1 - 1] += path1.coverage.unwrap() as f64;
+    }
+    if allele2 != 0 {
+        alt_covs[allele2 - 1] += path2.coverage.unwrap() as f64;
+    }
+
+    match (allele1, allele2) {
+        (0, 0) if coverage != 0 => ("0|0".to_string(), metrics::GTstate::Ref, alt_covs, true),
+        (0, 0) => ("./.".to_string(), metrics::GTstate::Non, alt_covs, true),
+        (_, 0) => (
+            format!("{}|0", allele1),
             metrics::GTstate::Het,
-            path1.coverage.unwrap() as f64,
+            alt_covs,
             path1.full_target,
         ),
-        (false, true) => (
-            "0|1",
+        (0, _) => (
+            format!("0|{}", allele2),
             metrics::GTstate::Het,
-            path2.coverage.unwrap() as f64,
+            alt_covs,
             path2.full_target,
         ),
-        (false, false) if coverage != 0 => ("0|0", metrics::GTstate::Ref, 0.0, true),
-        (false, false) => ("./.", metrics::GTstate::Non, 0.0, true),
+        (_, _) if allele1 == allele2 => (
+            format!("{}|{}", allele1, allele2),
+            metrics::GTstate::Hom,
+            alt_covs,
+            path1.full_target || path2.full_target,
+        ),
+        (_, _) => (
+            format!("{}|{}", allele1, allele2),
+            metrics::GTstate::HetAlt,
+            alt_covs,
+            path1.full_target || path2.full_target,
+        ),
     }
 }
 
 fn finalize_annotation(
-    entry: RecordBuf,
+    alleles: &[Option<NodeIndex>],
     handle: HandleReturn,
     paths: &[PathScore],
     coverage: u64,
+    ploidy: &Ploidy,
     neigh_group: u64,
+    model: &AfModel,
 ) -> GenotypeAnno {
-    let (gt_str, gt_path, alt_cov, full_target) = handle;
+    let (gt_str, gt_path, alt_covs, full_target) = handle;
+    let alt_cov: f64 = alt_covs.iter().sum();
     let ref_cov = coverage as f64 - alt_cov;
 
-    let gt_obs = metrics::genotyper(ref_cov, alt_cov);
-
     // we're now assuming that ref/alt are the coverages used for these genotypes. no bueno
-    let (gq, sq) = metrics::genotype_quals(ref_cov, alt_cov);
+    let (gt_obs, (gq, sq)) = match ploidy {
+        Ploidy::Polyploid(n) => (
+            metrics::dosage_state(metrics::dosage_genotyper(ref_cov, alt_cov, *n, model), *n),
+            metrics::dosage_quals(ref_cov, alt_cov, *n, model),
+        ),
+        _ => (
+            metrics::genotyper(ref_cov, alt_cov, model),
+            metrics::genotype_quals(ref_cov, alt_cov, model),
+        ),
+    };
 
     let ps = if !paths.is_empty() { paths[0].ps } else { None };
 
-    let ad = vec![Some(ref_cov as i32), Some(alt_cov as i32)];
+    let ad = std::iter::once(ref_cov)
+        .chain(alt_covs.iter().cloned())
+        .map(|cov| Some(cov as i32))
+        .collect();
 
-    let ks: Vec<Option<i32>> = paths
+    let hap_reads: Vec<(usize, Option<i32>, Vec<String>)> = paths
         .iter()
-        .map(|p| Some((p.score * 100.0) as i32))
+        .map(|p| {
+            (
+                path_allele(alleles, p),
+                Some((p.score * 100.0) as i32),
+                p.reads.clone(),
+            )
+        })
         .collect();
 
+    // Each allele's score is its best path's
+    let ks: Vec<Option<i32>> = (1..=alleles.len())
+        .map(|allele| {
+            hap_reads
+                .iter()
+                .filter(|(a, _, _)| *a == allele)
+                .filter_map(|(_, score, _)| *score)
+                .max()
+        })
+        .collect();
+
+    let (pl, gl) =
+        match metrics::genotype_likelihoods(ref_cov, &alt_covs, ploidy.value() as u8, model) {
+            Some(likes) if coverage != 0 => {
+                let best = likes.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                (
+                    likes
+                        .iter()
+                        .map(|l| Some((-10.0 * (l - best)).round() as i32))
+                        .collect(),
+                    likes.iter().map(|&l| Some(l as f32)).collect(),
+                )
+            }
+            _ => (vec![None], vec![None]),
+        };
+
     let mut filt = FiltFlags::PASS;
-    if gt_obs != gt_path {
+    // The coverage genotyper can't make HetAlt calls
+    if gt_path != metrics::GTstate::HetAlt && gt_obs != gt_path {
         filt |= FiltFlags::GTMISMATCH;
     }
 
@@ -255,9 +504,15 @@ fn finalize_annotation(
         filt |= FiltFlags::PARTIAL;
     }
 
+    if paths
+        .iter()
+        .any(|p| p.clipped && path_allele(alleles, p) != 0)
+    {
+        filt |= FiltFlags::CLIPPED;
+    }
+
     GenotypeAnno {
-        entry,
-        gt: gt_str.to_string(),
+        gt: gt_str,
         filt,
         sq: sq.round() as i32,
         gq: gq.round() as i32,
@@ -265,7 +520,52 @@ fn finalize_annotation(
         dp: coverage as i32,
         ad,
         ks,
+        pl,
+        gl,
         gt_state: gt_path,
         ne: neigh_group,
+        hap_reads,
+        ref_reads: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ref_path(coverage: u64) -> PathScore {
+        PathScore {
+            coverage: Some(coverage),
+            full_target: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn polyploid_ref_only_is_not_partial() {
+        let alleles = [Some(NodeIndex::new(1))];
+        let model = AfModel::default();
+        for (paths, coverage) in [(vec![], 20), (vec![ref_path(20)], 20), (vec![], 0)] {
+            let anno = polyploid(&alleles, &paths, coverage, 4, 0, &model);
+            assert!(!anno.filt.contains(FiltFlags::PARTIAL), "{}", anno.gt);
+        }
+    }
+
+    #[test]
+    fn polyploid_alt_path_keeps_partial() {
+        let alleles = [Some(NodeIndex::new(1))];
+        let path = PathScore {
+            path: vec![NodeIndex::new(1)],
+            ..ref_path(10)
+        };
+        let anno = polyploid(
+            &alleles,
+            &[path, ref_path(10)],
+            20,
+            4,
+            0,
+            &AfModel::default(),
+        );
+        assert!(anno.filt.contains(FiltFlags::PARTIAL));
     }
 }
@@ -1,9 +1,13 @@
 extern crate pretty_env_logger;
 
+use crate::kplib::{is_plup_v2, parse_region, plup_index_path, BedParser, PlupReader};
 use clap::{Parser, Subcommand};
 use rust_htslib::tbx::{self, Read as TbxRead};
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Parser, Clone, Debug)]
 #[command(name = "kanpig")]
@@ -20,12 +24,22 @@ pub trait KanpigParams: std::fmt::Debug {
 }
 
 #[derive(Subcommand, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Commands {
     #[command(about = "Genotype SVs")]
     Gt(GTArgs),
 
     #[command(about = "BAM/CRAM to Pileup Index")]
     Plup(PlupArgs),
+
+    #[command(about = "Combine Pileup Indexes")]
+    PlupMerge(PlupMergeArgs),
+
+    #[command(about = "Extract regions of a Pileup Index")]
+    PlupView(PlupViewArgs),
+
+    #[command(about = "Summarize a Pileup Index")]
+    PlupStats(PlupStatsArgs),
 }
 
 #[derive(Parser, Serialize, Deserialize, Debug, Clone)]
@@ -38,7 +52,7 @@ pub struct PlupArgs {
     #[arg(short, long)]
     pub reference: Option<PathBuf>,
 
-    /// Output plup (unsorted, uncompressed) [default: stdout]
+    /// Output plup.gz (sorted, bgzipped, and indexed) [default: stdout, uncompressed]
     #[arg(short, long)]
     pub output: Option<PathBuf>,
 
@@ -46,6 +60,21 @@ pub struct PlupArgs {
     #[arg(short, long, default_value_t = 1)]
     pub threads: usize,
 
+    /// Only index reads overlapping regions of this bed
+    #[arg(long)]
+    #[serde(default)]
+    pub bed: Option<PathBuf>,
+
+    /// Only index reads overlapping this region (chrom or chrom:start-end). Can be repeated
+    #[arg(long)]
+    #[serde(default)]
+    pub region: Vec<String>,
+
+    /// Merged intervals reads were indexed from. Set from --bed and --region
+    #[arg(skip)]
+    #[serde(default)]
+    pub intervals: Option<Vec<(String, u64, u64)>>,
+
     /// Minimum size of variant to index
     #[arg(long, default_value_t = 50)]
     pub sizemin: u32,
@@ -54,6 +83,11 @@ pub struct PlupArgs {
     #[arg(long, default_value_t = 10000)]
     pub sizemax: u32,
 
+    /// Merge a read's indels within this distance of one another (off=0)
+    #[arg(long, default_value_t = 0)]
+    #[serde(default)]
+    pub merge_gap: u64,
+
     /// Minimum mapq of reads to consider
     #[arg(long, default_value_t = 5)]
     pub mapq: u8,
@@ -62,6 +96,51 @@ pub struct PlupArgs {
     #[arg(long, default_value_t = 3840)]
     pub mapflag: u16,
 
+    /// Only use reads from this read group (RG tag)
+    #[arg(long)]
+    #[serde(default)]
+    pub read_group: Option<String>,
+
+    /// Only use reads from read groups of this sample (SM)
+    #[arg(long)]
+    #[serde(default)]
+    pub rg_sample: Option<String>,
+
+    /// Aux tag of reads' haplotag
+    #[arg(long, default_value = "HP")]
+    #[serde(default = "default_hp_tag")]
+    pub hp_tag: String,
+
+    /// Aux tag of reads' phase set
+    #[arg(long, default_value = "PS")]
+    #[serde(default = "default_ps_tag")]
+    pub ps_tag: String,
+
+    /// Use split alignments (SA tag) as evidence for large SVs
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub splits: bool,
+
+    /// Use soft-clipped sequence as partial evidence for insertions
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub clips: bool,
+
+    /// Keep base qualities of inserted sequence
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub quals: bool,
+
+    /// Keep each read's name, MAPQ, strand and flags
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub read_info: bool,
+
+    /// Write the compact binary plup (v2) format. Requires --output
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    pub binary: bool,
+
     /// Chunksize in Mbp
     #[arg(long, default_value_t = 25)]
     pub chunk_size: u64,
@@ -90,6 +169,185 @@ impl KanpigParams for PlupArgs {
             warn!("--sizemin is recommended to be at least 20");
         }
 
+        if let Some(bed) = &self.bed {
+            is_ok &= validate_file(bed, "--bed");
+        }
+
+        for region in self.region.iter() {
+            if parse_region(region).is_none() {
+                error!("--region {} is malformed", region);
+                is_ok = false;
+            }
+        }
+
+        is_ok &= validate_tag(&self.hp_tag, "--hp-tag");
+        is_ok &= validate_tag(&self.ps_tag, "--ps-tag");
+        is_ok &= validate_plup_output(&self.output, self.binary);
+
+        is_ok
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PlupMergeArgs {
+    /// Input plup.gz files
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Output plup.gz (sorted, bgzipped, and indexed) [default: stdout, uncompressed]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Write the compact binary plup (v2) format. Requires --output
+    #[arg(long, default_value_t = false)]
+    pub binary: bool,
+
+    /// Verbose logging
+    #[arg(long, default_value_t = false)]
+    pub debug: bool,
+}
+
+impl KanpigParams for PlupMergeArgs {
+    fn debug(&self) -> bool {
+        self.debug
+    }
+
+    fn validate(&self) -> bool {
+        let mut is_ok = true;
+
+        let mut headers = vec![];
+        for input in &self.inputs {
+            is_ok &= validate_plup_index(input, "--inputs");
+            if let Ok(header) = read_plup_header(input) {
+                headers.push((input, header));
+            }
+        }
+        if let Some(((_, first), others)) = headers.split_first() {
+            for (path, header) in others {
+                compare_plup_headers(first, header, &path.to_string_lossy());
+            }
+        }
+
+        is_ok &= validate_plup_output(&self.output, self.binary);
+        is_ok
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PlupViewArgs {
+    /// Input plup.gz
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output plup.gz (sorted, bgzipped, and indexed) [default: stdout, uncompressed]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Region to extract (chrom or chrom:start-end)
+    #[arg(long, conflicts_with = "bed")]
+    pub region: Option<String>,
+
+    /// Regions to extract
+    #[arg(long)]
+    pub bed: Option<PathBuf>,
+
+    /// Minimum size of variant to keep [default: plup's]
+    #[arg(long)]
+    pub sizemin: Option<u32>,
+
+    /// Maximum size of variant to keep [default: plup's]
+    #[arg(long)]
+    pub sizemax: Option<u32>,
+
+    /// Minimum mapq of reads to keep. Requires a plup made with --read-info [default: plup's]
+    #[arg(long)]
+    pub mapq: Option<u8>,
+
+    /// Remove reads matching flag. Requires a plup made with --read-info
+    #[arg(long)]
+    pub mapflag: Option<u16>,
+
+    /// Write the compact binary plup (v2) format. Requires --output
+    #[arg(long, default_value_t = false)]
+    pub binary: bool,
+
+    /// Verbose logging
+    #[arg(long, default_value_t = false)]
+    pub debug: bool,
+}
+
+impl KanpigParams for PlupViewArgs {
+    fn debug(&self) -> bool {
+        self.debug
+    }
+
+    fn validate(&self) -> bool {
+        let mut is_ok = validate_plup_index(&self.input, "--input");
+
+        if let Ok(header) = read_plup_header(&self.input) {
+            if self.sizemin.is_some_and(|s| s < header.sizemin) {
+                warn!(
+                    "plup created with --sizemin {}. Smaller --sizemin has no effect",
+                    header.sizemin
+                );
+            }
+            if self.sizemax.is_some_and(|s| s > header.sizemax) {
+                warn!(
+                    "plup created with --sizemax {}. Larger --sizemax has no effect",
+                    header.sizemax
+                );
+            }
+            if (self.mapq.is_some() || self.mapflag.is_some()) && !header.read_info {
+                error!("plup created without --read-info can't be filtered by --mapq/--mapflag");
+                is_ok = false;
+            }
+        }
+
+        if let Some(region) = &self.region {
+            if parse_region(region).is_none() {
+                error!("--region {} is malformed", region);
+                is_ok = false;
+            }
+        }
+
+        if let Some(bed) = &self.bed {
+            is_ok &= validate_file(bed, "--bed");
+        }
+
+        is_ok &= validate_plup_output(&self.output, self.binary);
+        is_ok
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PlupStatsArgs {
+    /// Input plup.gz
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Reference whose .fai contig lengths give depth
+    #[arg(short, long)]
+    pub reference: Option<PathBuf>,
+
+    /// Also write the summaries as JSON
+    #[arg(long)]
+    pub json: Option<PathBuf>,
+
+    /// Verbose logging
+    #[arg(long, default_value_t = false)]
+    pub debug: bool,
+}
+
+impl KanpigParams for PlupStatsArgs {
+    fn debug(&self) -> bool {
+        self.debug
+    }
+
+    fn validate(&self) -> bool {
+        let mut is_ok = validate_plup_index(&self.input, "--input");
+        if let Some(ref_path) = &self.reference {
+            is_ok &= validate_reference(ref_path);
+        }
         is_ok
     }
 }
@@ -109,9 +367,13 @@ pub struct IOParams {
     #[arg(short, long, help_heading = "I/O")]
     pub input: PathBuf,
 
-    /// Reads to genotype (indexed .bam, .cram, or .plup.gz)
-    #[arg(short, long, help_heading = "I/O")]
-    pub reads: PathBuf,
+    /// Reads to genotype (indexed .bam, .cram, or .plup.gz), one per sample. Use --manifest for samples with multiple files
+    #[arg(short, long, num_args = 1.., required_unless_present = "manifest", help_heading = "I/O")]
+    pub reads: Vec<PathBuf>,
+
+    /// Tab-delimited file of sample names and their reads (replaces --reads/--sample)
+    #[arg(long, conflicts_with_all = ["reads", "sample"], help_heading = "I/O")]
+    pub manifest: Option<PathBuf>,
 
     /// Reference genome
     #[arg(short = 'f', long, help_heading = "I/O")]
@@ -121,27 +383,98 @@ pub struct IOParams {
     #[arg(short, long, help_heading = "I/O")]
     pub out: Option<PathBuf>,
 
+    /// Output TSV of the reads supporting each variant's haplotypes
+    #[arg(long, help_heading = "I/O")]
+    pub evidence: Option<PathBuf>,
+
+    /// Output BAM (sorted and indexed) of the spanning reads tagged with their haplotypes
+    #[arg(long, help_heading = "I/O")]
+    pub haplotag: Option<PathBuf>,
+
     /// Number of threads
     #[arg(short, long, default_value_t = 1, help_heading = "I/O")]
     pub threads: usize,
 
-    /// Output VCF sample name
-    #[arg(long, help_heading = "I/O")]
-    pub sample: Option<String>,
+    /// Output VCF sample name, one per --reads
+    #[arg(long, num_args = 1.., help_heading = "I/O")]
+    pub sample: Vec<String>,
 
-    /// Bed file of non-diploid regions
+    /// Bed file of regions with a ploidy other than --ploidy
     #[arg(long, help_heading = "I/O")]
     pub ploidy_bed: Option<PathBuf>,
 
+    /// Ploidy of regions outside the --ploidy-bed
+    #[arg(long, default_value_t = 2, help_heading = "I/O")]
+    pub ploidy: u8,
+
     /// Regions to analyze
     #[arg(long, help_heading = "I/O")]
     pub bed: Option<PathBuf>,
 
+    /// Bed file of tandem repeat boundaries. Variants in a repeat are genotyped together
+    #[arg(long, help_heading = "I/O")]
+    pub tr_catalog: Option<PathBuf>,
+
+    /// Per-sample allele-fraction model (JSON) to genotype with
+    #[arg(long, conflicts_with = "fit_af_model", help_heading = "I/O")]
+    pub af_model: Option<PathBuf>,
+
+    /// Fit per-sample allele-fraction models from a first pass and write them to this file
+    #[arg(long, help_heading = "I/O")]
+    pub fit_af_model: Option<PathBuf>,
+
     /// Verbose logging
     #[arg(long, default_value_t = false)]
     pub debug: bool,
 }
 
+impl IOParams {
+    /// Pairs each sample's reads files with its name from --manifest or --reads/--sample
+    /// Names are None when --sample wasn't provided
+    pub fn sample_reads(&self) -> Vec<(Option<String>, Vec<PathBuf>)> {
+        match &self.manifest {
+            Some(path) => parse_manifest(path),
+            None => self
+                .reads
+                .iter()
+                .enumerate()
+                .map(|(idx, reads)| (self.sample.get(idx).cloned(), vec![reads.clone()]))
+                .collect(),
+        }
+    }
+}
+
+/// Parse a tab-delimited manifest of sample names and reads paths
+/// A sample's multiple reads files are in additional columns
+fn parse_manifest(path: &Path) -> Vec<(Option<String>, Vec<PathBuf>)> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        error!("unable to read --manifest {}", path.display());
+        std::process::exit(1);
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('\t') {
+            Some((name, reads)) => (
+                Some(name.to_string()),
+                reads
+                    .split('\t')
+                    .filter(|x| !x.is_empty())
+                    .map(PathBuf::from)
+                    .collect(),
+            ),
+            None => {
+                error!(
+                    "malformed manifest line (expected name<TAB>reads): {}",
+                    line
+                );
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
 #[derive(clap::Args, Clone, Debug)]
 pub struct KDParams {
     /// Only analyze variants with PASS FILTER
@@ -152,6 +485,10 @@ pub struct KDParams {
     #[arg(long, default_value_t = 1000, help_heading = "Variants & Reads")]
     pub neighdist: u64,
 
+    /// Flanking bases around --tr-catalog repeats which reads must span
+    #[arg(long, default_value_t = 100, help_heading = "Variants & Reads")]
+    pub tr_flank: u64,
+
     /// Minimum size of variant to analyze
     #[arg(long, default_value_t = 50, help_heading = "Variants & Reads")]
     pub sizemin: u32,
@@ -160,6 +497,10 @@ pub struct KDParams {
     #[arg(long, default_value_t = 10000, help_heading = "Variants & Reads")]
     pub sizemax: u32,
 
+    /// Merge a read's indels within this distance of one another (off=0)
+    #[arg(long, default_value_t = 0, help_heading = "Variants & Reads")]
+    pub merge_gap: u64,
+
     /// Minimum mapq score for reads
     #[arg(long, default_value_t = 5, help_heading = "Variants & Reads")]
     pub mapq: u8,
@@ -168,6 +509,42 @@ pub struct KDParams {
     #[arg(long, default_value_t = 3840, help_heading = "Variants & Reads")]
     pub mapflag: u16,
 
+    /// Downsample neighborhoods' spanning reads to at most this coverage (off=0)
+    #[arg(long, default_value_t = 0, help_heading = "Variants & Reads")]
+    pub max_coverage: u64,
+
+    /// Only use reads from this read group (RG tag)
+    #[arg(long, help_heading = "Variants & Reads")]
+    pub read_group: Option<String>,
+
+    /// Only use reads from read groups of this sample (SM)
+    #[arg(long, help_heading = "Variants & Reads")]
+    pub rg_sample: Option<String>,
+
+    /// Aux tag of reads' haplotag
+    #[arg(long, default_value = "HP", help_heading = "Variants & Reads")]
+    pub hp_tag: String,
+
+    /// Aux tag of reads' phase set
+    #[arg(long, default_value = "PS", help_heading = "Variants & Reads")]
+    pub ps_tag: String,
+
+    /// Use split alignments (SA tag) as evidence for large SVs
+    #[arg(long, default_value_t = false, help_heading = "Variants & Reads")]
+    pub splits: bool,
+
+    /// Use soft-clipped sequence as partial evidence for insertions
+    #[arg(long, default_value_t = false, help_heading = "Variants & Reads")]
+    pub clips: bool,
+
+    /// Maximum distance of a soft-clip from an insertion to be used as evidence
+    #[arg(long, default_value_t = 100, help_heading = "Variants & Reads")]
+    pub clipdist: u64,
+
+    /// Use reads which only partially span a neighborhood as evidence for the variants they cover
+    #[arg(long, default_value_t = false, help_heading = "Variants & Reads")]
+    pub partial_reads: bool,
+
     /// Clustering weight for haplotagged reads (off=0.0, full=1.0)
     #[arg(long, default_value_t = 1.0, help_heading = "Variants & Reads")]
     pub hps_weight: f32,
@@ -224,6 +601,10 @@ pub struct KDParams {
     #[arg(long, default_value_t = 0, help_heading = "Scoring / Advanced")]
     pub maxhom: usize,
 
+    /// (Experimental) Mask insertion kmers holding bases below this quality (off=0)
+    #[arg(long, default_value_t = 0, help_heading = "Scoring / Advanced")]
+    pub minbq: u8,
+
     /// (Experimental) Prefer simplier paths during scoring
     #[arg(long, default_value_t = false, help_heading = "Scoring / Advanced")]
     pub squish: bool,
@@ -242,13 +623,75 @@ impl KanpigParams for GTArgs {
         let mut is_ok = true;
 
         is_ok &= validate_file(&self.io.input, "--input");
-        is_ok &= validate_reads(&self.io.reads, self);
+        if let Some(manifest) = &self.io.manifest {
+            is_ok &= validate_file(manifest, "--manifest");
+        }
+        let samples = self.io.sample_reads();
+        for (name, reads) in &samples {
+            if reads.is_empty() {
+                error!(
+                    "no reads for sample {}",
+                    name.as_deref().unwrap_or_default()
+                );
+                is_ok = false;
+            }
+            is_ok &= validate_reads(reads, self);
+        }
+
+        if samples.is_empty() {
+            error!("no reads to genotype");
+            is_ok = false;
+        }
+
+        // A single --reads may take its sample name from the --input
+        if self.io.manifest.is_none()
+            && (samples.len() > 1 || !self.io.sample.is_empty())
+            && self.io.sample.len() != samples.len()
+        {
+            error!("--sample must provide one name per --reads");
+            is_ok = false;
+        }
+
+        if self.io.haplotag.is_some() {
+            if samples.len() != 1 || samples[0].1.len() != 1 {
+                error!("--haplotag requires a single sample with one reads file");
+                is_ok = false;
+            } else if samples[0].1[0].to_string_lossy().ends_with(".plup.gz") {
+                error!("--haplotag requires .bam or .cram --reads");
+                is_ok = false;
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for name in samples.iter().filter_map(|(name, _)| name.as_ref()) {
+            if !names.insert(name) {
+                error!("sample name {} is used more than once", name);
+                is_ok = false;
+            }
+        }
+
         is_ok &= validate_reference(&self.io.reference);
 
         if let Some(bed_file) = &self.io.bed {
             is_ok &= validate_file(bed_file, "--bed");
         }
 
+        if let Some(ploidy_bed) = &self.io.ploidy_bed {
+            is_ok &= validate_file(ploidy_bed, "--ploidy-bed");
+        }
+
+        if let Some(tr_catalog) = &self.io.tr_catalog {
+            is_ok &= validate_file(tr_catalog, "--tr-catalog");
+        }
+
+        if let Some(af_model) = &self.io.af_model {
+            is_ok &= validate_file(af_model, "--af-model");
+        }
+
+        if self.io.ploidy > 8 {
+            warn!("--ploidy above 8 needs high coverage to resolve dosages");
+        }
+
         if self.kd.sizemin < 10 {
             warn!("--sizemin is recommended to be at least 10");
         }
@@ -287,6 +730,9 @@ impl KanpigParams for GTArgs {
             is_ok = false;
         }
 
+        is_ok &= validate_tag(&self.kd.hp_tag, "--hp-tag");
+        is_ok &= validate_tag(&self.kd.ps_tag, "--ps-tag");
+
         if self.io.threads < 1 {
             error!("--threads must be at least 1");
             is_ok = false;
@@ -333,74 +779,294 @@ fn validate_bam(file_path: &str) -> bool {
 }
 
 fn validate_plup(file_path: &str, params: &GTArgs) -> bool {
-    let mut is_ok = true;
     if !file_path.ends_with(".plup.gz") {
-        is_ok = false;
+        return false;
+    }
+    let path = Path::new(file_path);
+    if !plup_index_exists(path) {
+        return false;
+    }
+
+    match read_plup_header(path) {
+        Ok(plup_args) => compare_plup_params(&plup_args, params),
+        Err(e) => error!(
+            "Failed to parse plup.gz header for parameter validation: {}",
+            e
+        ),
+    }
+    true
+}
+
+/// Checks a plup input exists and has its index
+fn validate_plup_index(path: &Path, label: &str) -> bool {
+    if !validate_file(path, label) {
+        return false;
+    }
+    if !path.to_string_lossy().ends_with(".plup.gz") {
+        error!("{} must end with .plup.gz", label);
+        return false;
+    }
+    plup_index_exists(path)
+}
+
+/// Text plups (v1) have a tabix index. Binary plups (v2) have a .pli
+fn plup_index_exists(path: &Path) -> bool {
+    if is_plup_v2(path) {
+        validate_file(&plup_index_path(path), "plup index (.pli)")
     } else {
-        let tbi_path = format!("{}.tbi", file_path);
-        if !validate_file(Path::new(&tbi_path), "plup index (.tbi)") {
-            is_ok = false;
-        } else {
-            let tbx = tbx::Reader::from_path(file_path).expect("Failed to open TBX file");
-            let header = tbx.header();
-            if header.len() != 1 {
-                error!("Malformed plup.gz header. Unable to validate parameters");
-            } else {
-                match serde_json::from_str::<PlupArgs>(&header[0][2..]) {
-                    Ok(plup_args) => {
-                        if plup_args.sizemin != params.kd.sizemin {
-                            warn!(
-                                "plup created with --sizemin {} != gt --sizemin {}",
-                                plup_args.sizemin, params.kd.sizemin
-                            );
-                        }
-
-                        if plup_args.sizemax != params.kd.sizemax {
-                            warn!(
-                                "plup created with --sizemax {} != gt --sizemax {}",
-                                plup_args.sizemax, params.kd.sizemax
-                            );
-                        }
-
-                        if plup_args.mapq != params.kd.mapq {
-                            warn!(
-                                "plup created with --mapq {} != gt --mapq {}",
-                                plup_args.mapq, params.kd.mapq
-                            );
-                        }
-
-                        if plup_args.mapflag != params.kd.mapflag {
-                            warn!(
-                                "plup created with --mapflag {} != gt --mapflag {}",
-                                plup_args.mapflag, params.kd.mapflag
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed to parse plup.gz header for parameter validation: {}",
-                            e
-                        );
-                    }
-                }
-            }
+        validate_file(
+            Path::new(&format!("{}.tbi", path.display())),
+            "plup index (.tbi)",
+        )
+    }
+}
+
+/// Plup outputs are bgzipped so they can be indexed. Binary output can't go to stdout
+fn validate_plup_output(output: &Option<PathBuf>, binary: bool) -> bool {
+    if let Some(output) = output {
+        if !output.to_string_lossy().ends_with(".plup.gz") {
+            error!("--output must end with .plup.gz");
+            return false;
         }
+    } else if binary {
+        error!("--binary requires --output");
+        return false;
+    }
+    true
+}
+
+/// Creation parameters of a text (v1) or binary (v2) plup
+pub fn read_plup_header(path: &Path) -> Result<PlupArgs, String> {
+    if is_plup_v2(path) {
+        return PlupReader::new(path)
+            .map(|reader| reader.params)
+            .map_err(|e| e.to_string());
+    }
+    let tbx = tbx::Reader::from_path(path).map_err(|e| e.to_string())?;
+    let header = tbx.header();
+    if header.len() != 1 {
+        return Err("Malformed plup.gz header".to_string());
+    }
+    serde_json::from_str::<PlupArgs>(&header[0][2..]).map_err(|e| e.to_string())
+}
+
+/// Creation parameters which change a plup's reads, by their command line name
+type ReadParams = Vec<(&'static str, String)>;
+
+/// Parameters shared by plup and gt which change the reads that are piled up.
+/// Both are built from this so a parameter can't be missed by the plup compatibility checks
+struct ReadSettings<'a> {
+    sizemin: u32,
+    sizemax: u32,
+    merge_gap: u64,
+    mapq: u8,
+    mapflag: u16,
+    splits: bool,
+    clips: bool,
+    read_group: &'a Option<String>,
+    rg_sample: &'a Option<String>,
+    hp_tag: &'a str,
+    ps_tag: &'a str,
+}
+
+impl<'a> From<&'a PlupArgs> for ReadSettings<'a> {
+    fn from(args: &'a PlupArgs) -> Self {
+        Self {
+            sizemin: args.sizemin,
+            sizemax: args.sizemax,
+            merge_gap: args.merge_gap,
+            mapq: args.mapq,
+            mapflag: args.mapflag,
+            splits: args.splits,
+            clips: args.clips,
+            read_group: &args.read_group,
+            rg_sample: &args.rg_sample,
+            hp_tag: &args.hp_tag,
+            ps_tag: &args.ps_tag,
+        }
+    }
+}
+
+impl<'a> From<&'a KDParams> for ReadSettings<'a> {
+    fn from(args: &'a KDParams) -> Self {
+        Self {
+            sizemin: args.sizemin,
+            sizemax: args.sizemax,
+            merge_gap: args.merge_gap,
+            mapq: args.mapq,
+            mapflag: args.mapflag,
+            splits: args.splits,
+            clips: args.clips,
+            read_group: &args.read_group,
+            rg_sample: &args.rg_sample,
+            hp_tag: &args.hp_tag,
+            ps_tag: &args.ps_tag,
+        }
+    }
+}
+
+impl ReadSettings<'_> {
+    fn read_params(&self) -> ReadParams {
+        // Destructured so a new setting has to be given a name here
+        let Self {
+            sizemin,
+            sizemax,
+            merge_gap,
+            mapq,
+            mapflag,
+            splits,
+            clips,
+            read_group,
+            rg_sample,
+            hp_tag,
+            ps_tag,
+        } = self;
+        vec![
+            ("--sizemin", sizemin.to_string()),
+            ("--sizemax", sizemax.to_string()),
+            ("--merge-gap", merge_gap.to_string()),
+            ("--mapq", mapq.to_string()),
+            ("--mapflag", mapflag.to_string()),
+            ("--splits", splits.to_string()),
+            ("--clips", clips.to_string()),
+            (
+                "--read-group/--rg-sample",
+                format!("{:?}/{:?}", read_group, rg_sample),
+            ),
+            ("--hp-tag/--ps-tag", format!("{}/{}", hp_tag, ps_tag)),
+        ]
+    }
+}
+
+/// Warns about each parameter of a plup which differs from another's
+fn warn_param_mismatch(plup: &ReadParams, other: &ReadParams, label: &str) {
+    for ((name, plup_value), (_, other_value)) in plup.iter().zip(other.iter()) {
+        if plup_value != other_value {
+            warn!(
+                "plup created with {} {} != {} {} {}",
+                name, plup_value, label, name, other_value
+            );
+        }
+    }
+}
+
+/// Warns about plup creation parameters which differ from gt's
+fn compare_plup_params(plup_args: &PlupArgs, params: &GTArgs) {
+    let mut plup_params = ReadSettings::from(plup_args).read_params();
+    let mut gt_params = ReadSettings::from(&params.kd).read_params();
+    // Reads with info are filtered at genotype time, so stricter filters take effect
+    if plup_args.read_info
+        && params.kd.mapq >= plup_args.mapq
+        && params.kd.mapflag & plup_args.mapflag == plup_args.mapflag
+    {
+        plup_params.retain(|(name, _)| !["--mapq", "--mapflag"].contains(name));
+        gt_params.retain(|(name, _)| !["--mapq", "--mapflag"].contains(name));
+    }
+    warn_param_mismatch(&plup_params, &gt_params, "gt");
+
+    if let Some(intervals) = &plup_args.intervals {
+        compare_plup_intervals(intervals, &params.io.bed);
+    }
+
+    if params.kd.minbq > 0 && !plup_args.quals {
+        warn!("plup created without --quals. gt --minbq will have no effect");
+    }
+}
+
+/// Warns when gt asks for regions outside of the intervals a plup was restricted to
+fn compare_plup_intervals(intervals: &[(String, u64, u64)], bed: &Option<PathBuf>) {
+    let Some(bed) = bed else {
+        warn!(
+            "plup created with --bed/--region. gt without --bed will have no reads outside of them"
+        );
+        return;
+    };
+
+    let mut lookup: HashMap<&str, Vec<(u64, u64)>> = HashMap::new();
+    for (chrom, start, end) in intervals {
+        lookup.entry(chrom).or_default().push((*start, *end));
+    }
+    // Intervals are merged, so a region is held when the last interval starting at or before it
+    // also covers its end
+    let outside: Vec<_> = BedParser::new(bed)
+        .parse()
+        .into_iter()
+        .filter(|entry| {
+            let Some(m_ivs) = lookup.get(entry.chrom.as_str()) else {
+                return true;
+            };
+            let idx = m_ivs.partition_point(|(start, _)| *start <= entry.start);
+            idx == 0 || m_ivs[idx - 1].1 < entry.end
+        })
+        .collect();
+
+    if let Some(entry) = outside.first() {
+        warn!(
+            "{} gt --bed regions are outside of the plup's --bed/--region (e.g. {}:{}-{})",
+            outside.len(),
+            entry.chrom,
+            entry.start,
+            entry.end
+        );
+    }
+}
+
+/// Warns about creation parameters which differ between plups being combined
+fn compare_plup_headers(plup_args: &PlupArgs, other: &PlupArgs, label: &str) {
+    warn_param_mismatch(
+        &ReadSettings::from(plup_args).read_params(),
+        &ReadSettings::from(other).read_params(),
+        label,
+    );
+
+    if plup_args.quals != other.quals {
+        warn!(
+            "plup created with --quals {} != {} --quals {}",
+            plup_args.quals, label, other.quals
+        );
+    }
+
+    if plup_args.read_info != other.read_info {
+        warn!(
+            "plup created with --read-info {} != {} --read-info {}",
+            plup_args.read_info, label, other.read_info
+        );
     }
-    is_ok
 }
+
 /// Helper function to validate reads (.bam, .cram, or .plup.gz)
-fn validate_reads(reads: &Path, params: &GTArgs) -> bool {
-    let mut is_ok = validate_file(reads, "--reads");
-    let file_path = reads.to_str().unwrap_or_default();
-    let bam_ok = validate_bam(file_path);
-    let plup_ok = validate_plup(file_path, params);
-    if !(bam_ok || plup_ok) {
-        error!("Unsupported file type: {}", file_path);
-        is_ok = false;
+fn validate_reads(reads: &[PathBuf], params: &GTArgs) -> bool {
+    let mut is_ok = true;
+    for m_reads in reads {
+        is_ok &= validate_file(m_reads, "--reads");
+        let file_path = m_reads.to_str().unwrap_or_default();
+        let bam_ok = validate_bam(file_path);
+        let plup_ok = validate_plup(file_path, params);
+        if !(bam_ok || plup_ok) {
+            error!("Unsupported file type: {}", file_path);
+            is_ok = false;
+        }
     }
     is_ok
 }
 
+/// Aux tags are two characters
+fn validate_tag(tag: &str, label: &str) -> bool {
+    if tag.len() != 2 || !tag.is_ascii() {
+        error!("{} must be a two character tag", label);
+        return false;
+    }
+    true
+}
+
+fn default_hp_tag() -> String {
+    "HP".to_string()
+}
+
+fn default_ps_tag() -> String {
+    "PS".to_string()
+}
+
 /// Checks reference and its .fai index
 fn validate_reference(reference: &Path) -> bool {
     let mut is_ok = validate_file(reference, "--reference");
@@ -1,8 +1,96 @@
-use crate::kplib::{metrics, Haplotype, KDParams};
+use crate::kplib::{metrics, AfModel, Haplotype, KDParams};
 use ndarray::Array2;
 use rand::SeedableRng;
 use std::collections::HashMap;
 
+/// 64-bit FNV-1a, which is fixed across releases and platforms unlike std's DefaultHasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Reads of a neighborhood left after downsampling
+pub struct Downsampled {
+    pub haps: Vec<Haplotype>,
+    pub partials: Vec<Haplotype>,
+    pub ref_reads: Vec<String>,
+    /// Spanning reads kept
+    pub coverage: u64,
+    /// Spanning reads removed
+    pub dropped: u64,
+    /// Spans of the partially spanning reads removed
+    pub dropped_partials: Vec<(u64, u64)>,
+}
+
+/// Deterministically subsample a neighborhood's reads to `max_coverage`
+/// The spanning reads with haplotypes (`haps`), partially spanning reads (`partials`) and spanning
+/// reference reads (`ref_reads`) are pooled and the `max_coverage` reads with the lowest hash of
+/// their name are kept, so the same reads are chosen on every run. Unnamed reads (e.g. from a
+/// plup) are hashed by their order.
+pub fn downsample_haplotypes(
+    haps: Vec<Haplotype>,
+    partials: Vec<Haplotype>,
+    ref_reads: Vec<String>,
+    coverage: u64,
+    max_coverage: u64,
+) -> Downsampled {
+    let total = haps.len() + partials.len() + ref_reads.len();
+    if max_coverage == 0 || total as u64 <= max_coverage {
+        return Downsampled {
+            haps,
+            partials,
+            ref_reads,
+            coverage,
+            dropped: 0,
+            dropped_partials: vec![],
+        };
+    }
+
+    let names = haps
+        .iter()
+        .chain(partials.iter())
+        .map(|hap| hap.reads.first().map(|name| name.as_str()).unwrap_or(""))
+        .chain(ref_reads.iter().map(|name| name.as_str()));
+    let mut keyed: Vec<(u64, usize)> = names
+        .enumerate()
+        .map(|(idx, name)| match name.is_empty() {
+            true => (fnv1a(&(idx as u64).to_le_bytes()), idx),
+            false => (fnv1a(name.as_bytes()), idx),
+        })
+        .collect();
+    keyed.sort_unstable();
+    let mut keep = vec![false; total];
+    for (_, idx) in keyed.iter().take(max_coverage as usize) {
+        keep[*idx] = true;
+    }
+
+    let n_haps = haps.len();
+    let n_partials = partials.len();
+    let (haps, lost_haps): (Vec<_>, Vec<_>) = haps.into_iter().zip(&keep).partition(|(_, k)| **k);
+    let (partials, lost_partials): (Vec<_>, Vec<_>) = partials
+        .into_iter()
+        .zip(&keep[n_haps..])
+        .partition(|(_, k)| **k);
+    let (ref_reads, lost_refs): (Vec<_>, Vec<_>) = ref_reads
+        .into_iter()
+        .zip(&keep[n_haps + n_partials..])
+        .partition(|(_, k)| **k);
+
+    let dropped = ((lost_haps.len() + lost_refs.len()) as u64).min(coverage);
+    Downsampled {
+        haps: haps.into_iter().map(|(hap, _)| hap).collect(),
+        partials: partials.into_iter().map(|(hap, _)| hap).collect(),
+        ref_reads: ref_reads.into_iter().map(|(name, _)| name).collect(),
+        coverage: coverage - dropped,
+        dropped,
+        dropped_partials: lost_partials
+            .into_iter()
+            .filter_map(|(hap, _)| hap.span)
+            .collect(),
+    }
+}
+
 /// Simply takes the best-covered haplotype as the representative
 pub fn haploid_haplotypes(
     mut haps: Vec<Haplotype>,
@@ -19,23 +107,44 @@ pub fn haploid_haplotypes(
     }
 
     let mut g_ps = None;
-    let hap_counts: HashMap<Haplotype, usize> =
-        haps.drain(..).fold(HashMap::new(), |mut acc, hap| {
+    // Reads are kept per haplotype so only those supporting the winner are reported
+    let hap_counts: HashMap<Haplotype, (usize, Vec<String>)> =
+        haps.drain(..).fold(HashMap::new(), |mut acc, mut hap| {
             g_ps = g_ps.or(hap.ps);
-            *acc.entry(hap).or_insert(0) += 1;
+            let reads = std::mem::take(&mut hap.reads);
+            let entry = acc.entry(hap).or_insert((0, vec![]));
+            entry.0 += 1;
+            entry.1.extend(reads);
             acc
         });
 
-    let (mut most_common_hap, _) = hap_counts
+    let (mut most_common_hap, (_, reads)) = hap_counts
         .into_iter()
-        .max_by(|(hap1, count1), (hap2, count2)| count1.cmp(count2).then_with(|| hap1.cmp(hap2)))
+        .max_by(|(hap1, (count1, _)), (hap2, (count2, _))| {
+            count1.cmp(count2).then_with(|| hap1.cmp(hap2))
+        })
         .expect("Must be >1 hap to get here");
     most_common_hap.coverage = cnt;
     most_common_hap.ps = g_ps;
+    most_common_hap.reads = reads;
 
     vec![most_common_hap]
 }
 
+/// Pairwise distances between haplotypes for kmedoids clustering
+fn haplotype_distances(haplos: &[Haplotype], params: &KDParams) -> Array2<f32> {
+    Array2::from_shape_fn((haplos.len(), haplos.len()), |(i, j)| {
+        // Convert similarity to distance
+        let dist =
+            1.0 - (metrics::seqsim(&haplos[i].kfeat, &haplos[j].kfeat, params.minkfreq as f32));
+        // Penalize only if both points have defined, different groups
+        match (haplos[i].hp, haplos[j].hp) {
+            (Some(group_i), Some(group_j)) if group_i != group_j => dist + params.hps_weight,
+            _ => dist,
+        }
+    })
+}
+
 /// Cluster multiple haplotypes together to try and reduce them to at most two haplotypes
 /// This is 'actually' the genotyper. Whatever come out of here is mapped to the variants
 /// So inaccurate descriptions of the two haplotypes can not produce good genotypes.
@@ -43,6 +152,7 @@ pub fn diploid_haplotypes(
     mut haplos: Vec<Haplotype>,
     coverage: u64,
     params: &KDParams,
+    model: &AfModel,
 ) -> Vec<Haplotype> {
     if coverage == 0 || haplos.is_empty() {
         return vec![];
@@ -54,18 +164,7 @@ pub fn diploid_haplotypes(
         return vec![hap];
     }
 
-    // Create a distance matrix
-    let distance_matrix: Array2<f32> =
-        Array2::from_shape_fn((haplos.len(), haplos.len()), |(i, j)| {
-            // Convert similarity to distance
-            let dist =
-                1.0 - (metrics::seqsim(&haplos[i].kfeat, &haplos[j].kfeat, params.minkfreq as f32));
-            // Penalize only if both points have defined, different groups
-            match (haplos[i].hp, haplos[j].hp) {
-                (Some(group_i), Some(group_j)) if group_i != group_j => dist + params.hps_weight,
-                _ => dist,
-            }
-        });
+    let distance_matrix = haplotype_distances(&haplos, params);
 
     let mut medoids = kmedoids::random_initialization(
         haplos.len(),
@@ -78,17 +177,20 @@ pub fn diploid_haplotypes(
     debug!("Loss: {}", loss);
 
     let mut haps = vec![haplos[medoids[0]].clone(), haplos[medoids[1]].clone()];
+    haps.iter_mut().for_each(|m_hap| m_hap.reads.clear());
     let mut hps_cnt = [[0, 0], [0, 0]];
 
     assignments
         .into_iter()
         .zip(haplos)
-        .for_each(|(idx, m_hap)| {
+        .for_each(|(idx, mut m_hap)| {
             let k_hap = &mut haps[idx];
             k_hap.coverage += 1;
             k_hap.ps = k_hap.ps.or(m_hap.ps);
+            k_hap.reads.append(&mut m_hap.reads);
 
-            if let Some(hp) = m_hap.hp {
+            // Only two haplotags in diploid regions
+            if let Some(hp) = m_hap.hp.filter(|hp| (1..=2).contains(hp)) {
                 hps_cnt[idx][hp as usize - 1] += 1;
             }
         });
@@ -122,6 +224,7 @@ pub fn diploid_haplotypes(
         && metrics::sizesim(hap1.size.unsigned_abs(), hap2.size.unsigned_abs()) > params.hapsim
     {
         hap2.coverage += hap1.coverage;
+        hap2.reads.append(&mut hap1.reads);
         return vec![hap2];
     };
 
@@ -136,10 +239,11 @@ pub fn diploid_haplotypes(
     // there's 1 or 2 alts. Now we figure out if its Het/Hom
     let applied_coverage = (hap1.coverage + hap2.coverage) as f64;
     let remaining_coverage = coverage as f64 - applied_coverage;
-    match metrics::genotyper(remaining_coverage, applied_coverage) {
+    match metrics::genotyper(remaining_coverage, applied_coverage, model) {
         // We need the one higher covered alt
         metrics::GTstate::Ref | metrics::GTstate::Het => {
             hap2.coverage += hap1.coverage;
+            hap2.reads.append(&mut hap1.reads);
             vec![hap2]
         }
         metrics::GTstate::Hom => {
@@ -154,3 +258,98 @@ pub fn diploid_haplotypes(
         _ => panic!("The genotyper can't do this, yet"),
     }
 }
+
+/// Cluster haplotypes into at most `ploidy` alternate alleles
+/// Clusters with similar sizes are consolidated and, beyond the highest covered allele,
+/// clusters whose coverage doesn't support at least one copy are left as reference coverage.
+pub fn polyploid_haplotypes(
+    mut haplos: Vec<Haplotype>,
+    coverage: u64,
+    ploidy: u8,
+    params: &KDParams,
+    model: &AfModel,
+) -> Vec<Haplotype> {
+    if coverage == 0 || haplos.is_empty() {
+        return vec![];
+    };
+
+    // Nothing to cluster
+    if haplos.len() == 1 {
+        let hap = haplos.pop().unwrap();
+        return vec![hap];
+    }
+
+    let distance_matrix = haplotype_distances(&haplos, params);
+
+    let k = (ploidy as usize).min(haplos.len());
+    let mut medoids = kmedoids::random_initialization(
+        haplos.len(),
+        k,
+        &mut rand::rngs::StdRng::seed_from_u64(21),
+    );
+
+    let (loss, assignments, _, _): (f32, _, _, _) =
+        kmedoids::fasterpam(&distance_matrix.view(), &mut medoids, 100);
+    debug!("Loss: {}", loss);
+
+    let mut haps: Vec<Haplotype> = medoids.iter().map(|&m| haplos[m].clone()).collect();
+    haps.iter_mut().for_each(|m_hap| m_hap.reads.clear());
+    let mut hps_cnt: Vec<HashMap<u8, usize>> = vec![HashMap::new(); k];
+
+    assignments
+        .into_iter()
+        .zip(haplos)
+        .for_each(|(idx, mut m_hap)| {
+            let k_hap = &mut haps[idx];
+            k_hap.coverage += 1;
+            k_hap.ps = k_hap.ps.or(m_hap.ps);
+            k_hap.reads.append(&mut m_hap.reads);
+
+            if let Some(hp) = m_hap.hp {
+                *hps_cnt[idx].entry(hp).or_insert(0) += 1;
+            }
+        });
+
+    // HP just takes most common
+    for (m_hap, hcnts) in haps.iter_mut().zip(hps_cnt) {
+        m_hap.coverage -= 1; // Correct overcounting above
+        m_hap.hp = hcnts
+            .into_iter()
+            .max_by(|(hp1, cnt1), (hp2, cnt2)| cnt1.cmp(cnt2).then_with(|| hp2.cmp(hp1)))
+            .map(|(hp, _)| hp);
+    }
+
+    // Highest covered alleles first
+    haps.sort_by_key(|m_hap| std::cmp::Reverse(m_hap.coverage));
+
+    // Dedup alleles which are highly similar
+    let mut ret: Vec<Haplotype> = Vec::with_capacity(haps.len());
+    for mut m_hap in haps {
+        match ret.iter_mut().find(|kept| {
+            kept.size.signum() == m_hap.size.signum()
+                && metrics::sizesim(kept.size.unsigned_abs(), m_hap.size.unsigned_abs())
+                    > params.hapsim
+        }) {
+            Some(kept) => {
+                kept.coverage += m_hap.coverage;
+                kept.reads.append(&mut m_hap.reads);
+            }
+            None => ret.push(m_hap),
+        }
+    }
+
+    // The highest covered allele is always kept. Others need support for at least one copy
+    let mut ret = ret.into_iter();
+    let mut kept = vec![ret.next().expect("at least one cluster")];
+    kept.extend(ret.filter(|m_hap| {
+        let alt_cov = m_hap.coverage as f64;
+        (alt_cov / coverage as f64) as f32 >= params.ab
+            && metrics::dosage_genotyper(coverage as f64 - alt_cov, alt_cov, ploidy, model) > 0
+    }));
+
+    for m_hap in kept.iter() {
+        debug!("Hap in {:?}", m_hap);
+    }
+
+    kept
+}
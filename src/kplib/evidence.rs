@@ -0,0 +1,85 @@
+use crate::kplib::VariantAnno;
+use noodles_vcf::variant::record::Ids;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Writes the reads supporting each sample's haplotypes at every variant as a TSV
+pub struct EvidenceWriter {
+    writer: BufWriter<File>,
+    samples: Vec<String>,
+}
+
+impl EvidenceWriter {
+    pub fn new(path: &Path, samples: &[String]) -> Self {
+        let file = File::create(path).expect("Error creating --evidence output");
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "#CHROM\tPOS\tID\tSAMPLE\tHAP\tALLELE\tKS\tREADS")
+            .expect("Unable to write --evidence output");
+        Self {
+            writer,
+            samples: samples.to_vec(),
+        }
+    }
+
+    /// One row per haplotype of each sample and a final row, with HAP '.', of the sample's
+    /// reference reads. Unnamed reads (e.g. from a plup without --read-info) are written as '.'
+    pub fn write(&mut self, anno: &VariantAnno) {
+        let chrom = anno.entry.reference_sequence_name();
+        let pos = anno.entry.variant_start().map_or(0, usize::from);
+        let ids = anno.entry.ids();
+        let id = if ids.is_empty() {
+            ".".to_string()
+        } else {
+            ids.iter().collect::<Vec<_>>().join(";")
+        };
+
+        for (sample, gt) in self.samples.iter().zip(&anno.samples) {
+            let mut rows: Vec<(String, usize, String, &[String])> = gt
+                .hap_reads
+                .iter()
+                .enumerate()
+                .map(|(hap, (allele, ks, reads))| {
+                    (
+                        hap.to_string(),
+                        *allele,
+                        ks.unwrap_or(0).to_string(),
+                        reads.as_slice(),
+                    )
+                })
+                .collect();
+            if !gt.ref_reads.is_empty() {
+                rows.push((".".to_string(), 0, ".".to_string(), &gt.ref_reads));
+            }
+
+            for (hap, allele, ks, reads) in rows {
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    chrom,
+                    pos,
+                    id,
+                    sample,
+                    hap,
+                    allele,
+                    ks,
+                    join_reads(reads)
+                )
+                .expect("Unable to write --evidence output");
+            }
+        }
+    }
+}
+
+fn join_reads(reads: &[String]) -> String {
+    if reads.is_empty() {
+        return ".".to_string();
+    }
+    reads
+        .iter()
+        .map(|name| if name.is_empty() { "." } else { name.as_str() })
+        .collect::<Vec<_>>()
+        .join(",")
+}
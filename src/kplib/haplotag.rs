@@ -0,0 +1,290 @@
+use crate::kplib::{PathScore, VariantAnno};
+use rust_htslib::bam::{self, record::Aux, CompressionLevel, HeaderView, Read as BamRead};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    path::{Path, PathBuf},
+};
+
+/// Tagged reads held in memory before they're sorted into a temporary run
+const RUN_SIZE: usize = 20_000;
+
+/// Output order of records. A read's records from multiple neighborhoods share a key
+type SortKey = (i32, i64, Vec<u8>, u16);
+
+fn sort_key(record: &bam::Record) -> SortKey {
+    (
+        record.tid(),
+        record.pos(),
+        record.qname().to_vec(),
+        record.flags(),
+    )
+}
+
+/// Writes the spanning reads of each neighborhood tagged with their kanpig haplotype
+/// Reads are sorted into temporary runs which are merged into the sorted and indexed output.
+/// A read spanning multiple neighborhoods is written once holding the tags of each.
+pub struct HaplotagWriter {
+    writer: bam::Writer,
+    out_path: PathBuf,
+    header: bam::Header,
+    buffer: Vec<bam::Record>,
+    runs: Vec<PathBuf>,
+}
+
+impl HaplotagWriter {
+    /// Output BAM with the header of the reads being genotyped
+    pub fn new(out_path: &Path, reads: &Path, reference: &Path) -> Self {
+        let mut reader = bam::Reader::from_path(reads).expect("Unable to open reads");
+        let _ = reader.set_reference(reference);
+        let header = sorted_header(reader.header());
+        let writer = bam::Writer::from_path(out_path, &header, bam::Format::Bam)
+            .expect("Error creating --haplotag output");
+        Self {
+            writer,
+            out_path: out_path.to_path_buf(),
+            header,
+            buffer: vec![],
+            runs: vec![],
+        }
+    }
+
+    pub fn write(&mut self, records: Vec<bam::Record>) {
+        self.buffer.extend(records);
+        if self.buffer.len() >= RUN_SIZE {
+            self.write_run();
+        }
+    }
+
+    /// Sorts the buffered reads into a temporary file next to the output
+    fn write_run(&mut self) {
+        self.buffer.sort_by_cached_key(sort_key);
+        let path = self
+            .out_path
+            .with_extension(format!("tmp{}.bam", self.runs.len()));
+        let mut writer = bam::Writer::from_path(&path, &self.header, bam::Format::Bam)
+            .expect("Unable to create --haplotag temporary file");
+        writer
+            .set_compression_level(CompressionLevel::Fastest)
+            .expect("Unable to create --haplotag temporary file");
+        for record in self.buffer.drain(..) {
+            writer
+                .write(&record)
+                .expect("Unable to write --haplotag temporary file");
+        }
+        self.runs.push(path);
+    }
+
+    /// Merges the runs and remaining reads into the output, removes the runs and indexes the output
+    pub fn finish(mut self) {
+        self.buffer.sort_by_cached_key(sort_key);
+        let mut sources: Vec<Box<dyn Iterator<Item = bam::Record>>> = self
+            .runs
+            .iter()
+            .map(|path| {
+                let mut reader =
+                    bam::Reader::from_path(path).expect("Unable to open --haplotag temporary file");
+                let records = std::iter::from_fn(move || {
+                    let mut record = bam::Record::new();
+                    reader.read(&mut record).map(|r| {
+                        r.expect("Unable to read --haplotag temporary file");
+                        record
+                    })
+                });
+                Box::new(records) as Box<dyn Iterator<Item = bam::Record>>
+            })
+            .collect();
+        sources.push(Box::new(std::mem::take(&mut self.buffer).into_iter()));
+
+        let mut heads: Vec<Option<bam::Record>> = sources.iter_mut().map(|s| s.next()).collect();
+        let mut heap: BinaryHeap<Reverse<(SortKey, usize)>> = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, head)| head.as_ref().map(|r| Reverse((sort_key(r), idx))))
+            .collect();
+
+        let mut pending: Option<(SortKey, bam::Record)> = None;
+        while let Some(Reverse((key, idx))) = heap.pop() {
+            let record = heads[idx]
+                .take()
+                .expect("Heap holds a record of each source");
+            if let Some(next) = sources[idx].next() {
+                heap.push(Reverse((sort_key(&next), idx)));
+                heads[idx] = Some(next);
+            }
+            match pending.as_mut() {
+                Some((p_key, p_record)) if *p_key == key => merge_tags(p_record, &record),
+                _ => {
+                    if let Some((_, p_record)) = pending.replace((key, record)) {
+                        self.writer
+                            .write(&p_record)
+                            .expect("Unable to write --haplotag output");
+                    }
+                }
+            }
+        }
+        if let Some((_, p_record)) = pending {
+            self.writer
+                .write(&p_record)
+                .expect("Unable to write --haplotag output");
+        }
+
+        // Output must be closed before it's indexed
+        drop(sources);
+        drop(self.writer);
+        for path in self.runs.iter() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Unable to remove {}: {}", path.display(), e);
+            }
+        }
+        if let Err(e) = bam::index::build(&self.out_path, None, bam::index::Type::Bai, 1) {
+            error!("Unable to index {}: {}", self.out_path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Header of the reads marked as coordinate sorted
+fn sorted_header(view: &HeaderView) -> bam::Header {
+    let text = String::from_utf8_lossy(view.as_bytes());
+    let version = text
+        .lines()
+        .find(|line| line.starts_with("@HD"))
+        .and_then(|line| line.split('\t').find_map(|f| f.strip_prefix("VN:")))
+        .unwrap_or("1.6");
+    let mut lines = vec![format!("@HD\tVN:{}\tSO:coordinate", version)];
+    lines.extend(
+        text.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("@HD"))
+            .map(String::from),
+    );
+    bam::Header::from_template(&HeaderView::from_bytes(lines.join("\n").as_bytes()))
+}
+
+/// Haplotypes (KH) and neighborhoods (NE) a record is tagged with
+fn read_tags(record: &bam::Record) -> Vec<(u32, u8)> {
+    let haps: Vec<u8> = match record.aux(b"KH") {
+        Ok(Aux::ArrayU8(arr)) => arr.iter().collect(),
+        _ => vec![],
+    };
+    let neighs: Vec<u32> = match record.aux(b"NE") {
+        Ok(Aux::ArrayU32(arr)) => arr.iter().collect(),
+        _ => vec![],
+    };
+    neighs.into_iter().zip(haps).collect()
+}
+
+fn set_tags(record: &mut bam::Record, tags: &[(u32, u8)]) {
+    let neighs: Vec<u32> = tags.iter().map(|(ne, _)| *ne).collect();
+    let haps: Vec<u8> = tags.iter().map(|(_, hap)| *hap).collect();
+    let _ = record.remove_aux(b"KH");
+    let _ = record.remove_aux(b"NE");
+    record
+        .push_aux(b"KH", Aux::ArrayU8((&haps).into()))
+        .expect("Unable to tag read");
+    record
+        .push_aux(b"NE", Aux::ArrayU32((&neighs).into()))
+        .expect("Unable to tag read");
+}
+
+/// Adds the tags of another record of the same read, ordered by neighborhood
+fn merge_tags(record: &mut bam::Record, other: &bam::Record) {
+    let mut tags = read_tags(record);
+    tags.extend(read_tags(other));
+    tags.sort_unstable();
+    tags.dedup();
+    set_tags(record, &tags);
+}
+
+/// Haplotype (1-based position in the GT) of each path and of the reference reads.
+/// Paths take the first unclaimed GT position holding their allele in any of the neighborhood's
+/// calls. Reference reads take the one position which is the reference allele in every call. Paths
+/// which never hold an alternate allele are on that reference haplotype. Unknown haplotypes are 0
+fn gt_haplotypes(annotated: &[VariantAnno], n_paths: usize) -> (Vec<u8>, u8) {
+    let mut path_haps = vec![0u8; n_paths];
+    let mut ref_slots: Option<Vec<bool>> = None;
+    for anno in annotated {
+        let Some(sample) = anno.samples.first() else {
+            continue;
+        };
+        let slots: Vec<Option<usize>> = sample
+            .gt
+            .split(['|', '/'])
+            .map(|allele| allele.parse().ok())
+            .collect();
+        if slots.iter().all(Option::is_none) {
+            continue;
+        }
+
+        let mut taken = vec![false; slots.len()];
+        for (hap, (allele, _, _)) in path_haps.iter_mut().zip(&sample.hap_reads) {
+            if *allele == 0 {
+                continue;
+            }
+            if let Some(pos) = (0..slots.len()).find(|&i| !taken[i] && slots[i] == Some(*allele)) {
+                taken[pos] = true;
+                if *hap == 0 {
+                    *hap = (pos + 1) as u8;
+                }
+            }
+        }
+
+        let is_ref: Vec<bool> = slots.iter().map(|slot| *slot == Some(0)).collect();
+        ref_slots = Some(match ref_slots {
+            None => is_ref,
+            Some(prev) => prev.iter().zip(is_ref).map(|(a, b)| *a && b).collect(),
+        });
+    }
+
+    let ref_hap = match ref_slots {
+        Some(slots) if slots.iter().filter(|s| **s).count() == 1 => {
+            (slots.iter().position(|s| *s).unwrap() + 1) as u8
+        }
+        _ => 0,
+    };
+    // A reference-only path is the reference haplotype unless another path already claimed it
+    if ref_hap != 0 && !path_haps.contains(&ref_hap) {
+        for (idx, hap) in path_haps.iter_mut().enumerate() {
+            let alt_free = annotated.iter().all(|anno| {
+                anno.samples
+                    .first()
+                    .and_then(|s| s.hap_reads.get(idx))
+                    .is_none_or(|(allele, _, _)| *allele == 0)
+            });
+            if *hap == 0 && alt_free {
+                *hap = ref_hap;
+                break;
+            }
+        }
+    }
+    (path_haps, ref_hap)
+}
+
+/// Tags reads with the haplotype (KH) they were assigned and their neighborhood (NE)
+/// Haplotypes are the positions in the sample's called GTs (1, 2, ...) so they match the phased
+/// VCF. Reference reads are on the haplotype holding the reference allele. Unassigned reads have 0
+pub fn haplotag_records(
+    records: &mut [bam::Record],
+    annotated: &[VariantAnno],
+    paths: &[PathScore],
+    ref_reads: &[String],
+    neigh_group: u64,
+) {
+    let neigh = u32::try_from(neigh_group).expect("Neighborhood is beyond the NE tag's range");
+    let (path_haps, ref_hap) = gt_haplotypes(annotated, paths.len());
+    let haps: HashMap<&str, u8> = paths
+        .iter()
+        .zip(path_haps)
+        .flat_map(|(path, hap)| path.reads.iter().map(move |name| (name.as_str(), hap)))
+        .chain(ref_reads.iter().map(|name| (name.as_str(), ref_hap)))
+        .collect();
+
+    for record in records.iter_mut() {
+        let hap = std::str::from_utf8(record.qname())
+            .ok()
+            .and_then(|name| haps.get(name))
+            .copied()
+            .unwrap_or(0);
+        set_tags(record, &[(neigh, hap)]);
+    }
+}
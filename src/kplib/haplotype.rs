@@ -12,8 +12,12 @@ pub struct Haplotype {
     pub n: u64,
     pub coverage: u64,
     pub kfeat: Vec<f32>,
-    pub parts: Vec<(i64, Vec<f32>)>,
+    pub parts: Vec<(i64, Vec<f32>, bool, u64)>,
     pub partial: usize,
+    pub clipped: usize,
+    pub inv_span: u64,
+    pub span: Option<(u64, u64)>, // Reference span of a read which doesn't span the neighborhood
+    pub reads: Vec<String>,       // Names of the reads supporting the haplotype
     pub ps: Option<u32>,
     pub hp: Option<u8>,
 }
@@ -32,8 +36,12 @@ impl Haplotype {
             n,
             coverage,
             kfeat: kfeat.clone(),
-            parts: vec![(size, kfeat)],
+            parts: vec![(size, kfeat, false, 0)],
             partial: 0,
+            clipped: 0,
+            inv_span: 0,
+            span: None,
+            reads: vec![],
             ps,
             hp,
         }
@@ -49,11 +57,34 @@ impl Haplotype {
             kfeat: mk.clone(),
             parts: vec![],
             partial: 0,
+            clipped: 0,
+            inv_span: 0,
+            span: None,
+            reads: vec![],
             ps: None,
             hp: None,
         }
     }
 
+    // Flag a variant as only partially observed in soft-clipped sequence
+    pub fn clip(mut self) -> Haplotype {
+        self.clipped = 1;
+        self.parts.iter_mut().for_each(|p| p.2 = true);
+        self
+    }
+
+    // Flag a variant as an inversion of span bases. Inversions don't change the size
+    pub fn invert(mut self, span: u64) -> Haplotype {
+        self.inv_span = span;
+        self.parts.iter_mut().for_each(|p| p.3 = span);
+        self
+    }
+
+    // Does the haplotype's read cover the start/end. Reads spanning the neighborhood cover everything
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        self.span.is_none_or(|(s, e)| s <= start && end <= e)
+    }
+
     // Add another variant to a Haplotype
     pub fn add(&mut self, other: &Haplotype) {
         if !self.kfeat.len() == other.kfeat.len() {
@@ -65,7 +96,14 @@ impl Haplotype {
             .for_each(|(x, y)| *x += y);
         self.size += other.size;
         self.n += 1;
-        self.parts.push((other.size, other.kfeat.clone()));
+        self.clipped += other.clipped;
+        self.inv_span += other.inv_span;
+        self.parts.push((
+            other.size,
+            other.kfeat.clone(),
+            other.clipped > 0,
+            other.inv_span,
+        ));
     }
 
     pub fn partial_haplotypes(&self, kmer: u8, max_fns: usize, max_parts: usize) -> Vec<Haplotype> {
@@ -87,6 +125,8 @@ impl Haplotype {
                         .zip(k.1.iter())
                         .for_each(|(x, y)| *x += y);
                     cur_hap.n += 1;
+                    cur_hap.clipped += k.2 as usize;
+                    cur_hap.inv_span += k.3;
                 }
                 cur_hap.partial = m_len - i;
                 ret.push(cur_hap);
@@ -160,8 +200,10 @@ impl Debug for Haplotype {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("Haplotype")
             .field("size", &self.size)
+            .field("inv_span", &self.inv_span)
             .field("n", &self.n)
             .field("coverage", &self.coverage)
+            .field("span", &self.span)
             .field("ps", &self.ps)
             .field("hp", &self.hp)
             // Exclude kfeat from the debug output
@@ -80,6 +80,53 @@ pub fn seq_to_kmer(sequence: &[u8], kmer: u8, negative: bool, maxhom: usize) ->
     kcounts
 }
 
+/// Converts an inserted sequence into positive k-mer counts, masking low-quality bases.
+/// K-mers holding any base with a quality below `minbq` aren't counted so that noisy
+/// sequence has less effect on similarity.
+///
+/// # Parameters
+/// - `sequence`: A slice of bytes representing the DNA sequence.
+/// - `quals`: Phred base qualities of `sequence`.
+/// - `kmer`: The length of the k-mers to count.
+/// - `maxhom`: The maximum length of homopolymers; if non-zero, compresses homopolymers before counting k-mers.
+/// - `minbq`: The minimum base quality of a k-mer's bases.
+///
+/// # Returns
+/// A vector of k-mer counts represented as floats.
+pub fn qual_seq_to_kmer(
+    sequence: &[u8],
+    quals: &[u8],
+    kmer: u8,
+    maxhom: usize,
+    minbq: u8,
+) -> Vec<f32> {
+    if maxhom != 0 {
+        let keep = homopolymer_keep(sequence, maxhom);
+        let (sequence, quals): (Vec<u8>, Vec<u8>) = sequence
+            .iter()
+            .zip(quals)
+            .zip(keep)
+            .filter_map(|(pair, k)| k.then_some(pair))
+            .unzip();
+        return qual_seq_to_kmer(&sequence, &quals, kmer, 0, minbq);
+    }
+
+    let ukmer = kmer as usize;
+    let mut kcounts = vec![0f32; 1 << (2 * ukmer)];
+
+    for (k_seq, k_qual) in sequence.windows(ukmer).zip(quals.windows(ukmer)) {
+        if k_qual.iter().any(|q| *q < minbq) {
+            continue;
+        }
+        let f_result = k_seq
+            .iter()
+            .fold(0u64, |acc, nuc| (acc << 2) + encode_nuc(*nuc));
+        kcounts[f_result as usize] += 1.0;
+    }
+
+    kcounts
+}
+
 /// Compresses sequences of repeated bytes (homopolymers) in the input vector.
 /// Limits the length of any sequence of repeated bytes to `maxspan`.
 ///
@@ -90,7 +137,16 @@ pub fn seq_to_kmer(sequence: &[u8], kmer: u8, negative: bool, maxhom: usize) ->
 /// # Returns
 /// A vector of bytes with homopolymer lengths limited to `maxspan`.
 pub fn compress_homopolymer(vector: &[u8], maxspan: usize) -> Vec<u8> {
-    let mut result = Vec::new();
+    vector
+        .iter()
+        .zip(homopolymer_keep(vector, maxspan))
+        .filter_map(|(byte, k)| k.then_some(*byte))
+        .collect()
+}
+
+/// Which bytes of the input vector are kept by `compress_homopolymer`
+fn homopolymer_keep(vector: &[u8], maxspan: usize) -> Vec<bool> {
+    let mut result = Vec::with_capacity(vector.len());
     let mut count = 0;
     let mut prev_byte = None;
 
@@ -98,13 +154,11 @@ pub fn compress_homopolymer(vector: &[u8], maxspan: usize) -> Vec<u8> {
         match prev_byte {
             Some(prev) if prev == byte => {
                 count += 1;
-                if count < maxspan {
-                    result.push(*byte);
-                }
+                result.push(count < maxspan);
             }
             _ => {
                 count = 1;
-                result.push(*byte);
+                result.push(true);
             }
         }
         prev_byte = Some(byte);
@@ -112,3 +166,28 @@ pub fn compress_homopolymer(vector: &[u8], maxspan: usize) -> Vec<u8> {
 
     result
 }
+
+/// Reverse complement of a DNA sequence. Non-ACGT bases are kept as-is.
+///
+/// # Parameters
+/// - `sequence`: A slice of bytes representing the DNA sequence.
+///
+/// # Returns
+/// A vector of bytes holding the reverse complement.
+pub fn reverse_complement(sequence: &[u8]) -> Vec<u8> {
+    sequence
+        .iter()
+        .rev()
+        .map(|nuc| match nuc {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            _ => *nuc,
+        })
+        .collect()
+}
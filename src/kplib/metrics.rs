@@ -1,3 +1,4 @@
+use crate::kplib::AfModel;
 use ordered_float::OrderedFloat;
 
 /// Computes the Canberra distance similarity between two featurized k-mer vectors.
@@ -36,6 +37,41 @@ pub fn seqsim(a: &[f32], b: &[f32], mink: f32) -> f32 {
     1.0 - (neum / deno)
 }
 
+/// Computes how much of a partially observed featurized k-mer vector is contained in another.
+/// Unlike `seqsim`, k-mers in `a` which aren't in `b` aren't penalized.
+///
+/// # Parameters
+/// - `a`: A slice of floating-point numbers representing the containing k-mer vector.
+/// - `b`: A slice of floating-point numbers representing the partial k-mer vector.
+/// - `mink`: A floating-point threshold below which differences are ignored.
+///
+/// # Returns
+/// A floating-point value representing the fraction of `b` found in `a`:
+/// - 1.0 indicates all of `b` is in `a`.
+/// - 0.0 indicates no kmers or none of `b` is in `a`.
+pub fn containsim(a: &[f32], b: &[f32], mink: f32) -> f32 {
+    let mut deno: f32 = 0.0;
+    let mut neum: f32 = 0.0;
+
+    for (&x, &y) in a.iter().zip(b.iter()) {
+        if x.abs() + y.abs() >= mink {
+            deno += y.abs();
+            // Only the part of b missing from a
+            neum += if y > 0.0 {
+                (y - x).max(0.0)
+            } else {
+                (x - y).max(0.0)
+            };
+        }
+    }
+
+    if deno == 0.0 {
+        return 0.0;
+    }
+
+    1.0 - (neum / deno).min(1.0)
+}
+
 /// Computes size similarity
 /// The similarity is defined as the ratio of the smaller size to the larger size,
 /// with special handling for cases where either size is zero.
@@ -78,6 +114,7 @@ pub enum GTstate {
     Ref,
     Het,
     Hom,
+    HetAlt, // Two different alternate alleles (e.g. 1|2)
     Non,
     //Hemi should be a thing
 }
@@ -89,17 +126,18 @@ pub enum GTstate {
 /// # Parameters
 /// - `alt1_cov`: The coverage value for the first alternate allele as a floating-point number.
 /// - `alt2_cov`: The coverage value for the second alternate allele as a floating-point number.
+/// - `model`: The sample's expected allele-fractions of each genotype.
 ///
 /// # Returns
 /// A `GTstate` enum value representing the genotype state
 ///
 /// # Panics
 /// This function will panic if an invalid state is encountered, which should be impossible under normal circumstances.
-pub fn genotyper(alt1_cov: f64, alt2_cov: f64) -> GTstate {
+pub fn genotyper(alt1_cov: f64, alt2_cov: f64, model: &AfModel) -> GTstate {
     if (alt1_cov + alt2_cov) == 0.0 {
         return GTstate::Non;
     }
-    let ret = match genotype_scores(alt1_cov, alt2_cov)
+    let ret = match genotype_scores(alt1_cov, alt2_cov, model)
         .iter()
         .enumerate()
         .max_by_key(|&(_, &x)| OrderedFloat(x))
@@ -116,26 +154,22 @@ pub fn genotyper(alt1_cov: f64, alt2_cov: f64) -> GTstate {
 
 /// Calculates genotype scores for three possible genotypes (reference, heterozygous, homozygous)
 /// based on the coverage values for two alternate alleles.
-/// The scores are adjusted based on the total coverage to account for lower coverage scenarios.
+/// The model's expected allele-fractions depend on the total coverage to account for lower coverage scenarios.
 ///
 /// # Parameters
 /// - `alt1_cov`: The coverage value for the first alternate allele as a floating-point number.
 /// - `alt2_cov`: The coverage value for the second alternate allele as a floating-point number.
+/// - `model`: The sample's expected allele-fractions of each genotype.
 ///
 /// # Returns
 /// An array of three floating-point values representing the log-probabilities for each genotype:
 /// - The first value corresponds to the reference genotype.
 /// - The second value corresponds to the heterozygous genotype.
 /// - The third value corresponds to the homozygous genotype.
-fn genotype_scores(alt1_cov: f64, alt2_cov: f64) -> [f64; 3] {
-    // Needs to be more pure for lower coverage
-    let p_alt: &[f64] = if alt1_cov + alt2_cov < 10.0 {
-        &[1e-3, 0.55, 0.95]
-    } else {
-        &[1e-3, 0.50, 0.90]
-    };
-
+fn genotype_scores(alt1_cov: f64, alt2_cov: f64, model: &AfModel) -> [f64; 3] {
     let total = alt1_cov + alt2_cov;
+    let p_alt = model.p_alt(total);
+
     let log_combo = log_choose(total, alt2_cov);
 
     [
@@ -150,14 +184,19 @@ fn genotype_scores(alt1_cov: f64, alt2_cov: f64) -> [f64; 3] {
 /// # Parameters
 /// - `ref_cov`: The coverage value for the reference allele as a floating-point number.
 /// - `alt_cov`: The coverage value for the alternate allele as a floating-point number.
+/// - `model`: The sample's expected allele-fractions of each genotype.
 ///
 /// # Returns
 /// A tuple containing two floating-point values:
 /// - The first value is the genotype quality (GQ).
 /// - The second value is the sample quality (SQ).
-pub fn genotype_quals(ref_cov: f64, alt_cov: f64) -> (f64, f64) {
-    let mut gt_lplist = genotype_scores(ref_cov, alt_cov);
+pub fn genotype_quals(ref_cov: f64, alt_cov: f64, model: &AfModel) -> (f64, f64) {
+    scores_to_quals(genotype_scores(ref_cov, alt_cov, model).to_vec())
+}
 
+/// Calculates genotype quality (GQ) and sample quality (SQ) from genotype log-probabilities
+/// where the first genotype is the reference.
+fn scores_to_quals(mut gt_lplist: Vec<f64>) -> (f64, f64) {
     let mut gt_sum = 0.0;
     for gt in &gt_lplist {
         gt_sum += 10.0_f64.powf(*gt);
@@ -173,14 +212,153 @@ pub fn genotype_quals(ref_cov: f64, alt_cov: f64) -> (f64, f64) {
     (gq, sq)
 }
 
+/// Calculates genotype likelihoods in the VCF `Number=G` order for every possible genotype.
+///
+/// # Parameters
+/// - `ref_cov`: The coverage value for the reference allele as a floating-point number.
+/// - `alt_covs`: The coverage value of each alternate allele.
+/// - `ploidy`: The number of alleles in the genotype.
+/// - `model`: The sample's expected allele-fractions of each genotype.
+///
+/// # Returns
+/// - `Some(Vec<f64>)`: log10 likelihoods of each genotype. Haploid genotypes are ordered by allele.
+///   Diploid genotype `j/k` (j <= k) is at index `k * (k + 1) / 2 + j`. Polyploid genotypes are
+///   ordered by alternate allele dosage.
+/// - `None`: Polyploid genotypes of multi-allelic variants aren't supported.
+///
+/// # Function Details
+/// - Genotypes are scored with `genotype_scores` where reads supporting an allele absent from the
+///   genotype count towards the other alleles. Two different alternate alleles are scored as a
+///   homozygous alternate with their coverage balance tested against an even split.
+/// - Biallelic diploid likelihoods are exactly the `genotype_scores` used for GQ and SQ.
+pub fn genotype_likelihoods(
+    ref_cov: f64,
+    alt_covs: &[f64],
+    ploidy: u8,
+    model: &AfModel,
+) -> Option<Vec<f64>> {
+    let total = ref_cov + alt_covs.iter().sum::<f64>();
+    // allele 0 is the reference
+    let cov = |allele: usize| match allele {
+        0 => ref_cov,
+        a => alt_covs[a - 1],
+    };
+    let n_alleles = alt_covs.len() + 1;
+
+    match ploidy {
+        1 => Some(
+            (0..n_alleles)
+                .map(|a| match a {
+                    0 => genotype_scores(cov(0), total - cov(0), model)[0],
+                    a => genotype_scores(total - cov(a), cov(a), model)[2],
+                })
+                .collect(),
+        ),
+        2 => {
+            let mut ret = Vec::with_capacity(n_alleles * (n_alleles + 1) / 2);
+            for k in 0..n_alleles {
+                for j in 0..=k {
+                    ret.push(match (j, k) {
+                        (0, 0) => genotype_scores(cov(0), total - cov(0), model)[0],
+                        (0, k) => genotype_scores(total - cov(k), cov(k), model)[1],
+                        (j, k) if j == k => genotype_scores(total - cov(k), cov(k), model)[2],
+                        (j, k) => {
+                            let alt = cov(j) + cov(k);
+                            genotype_scores(total - alt, alt, model)[2]
+                                + log_choose(alt, cov(k))
+                                + alt * 0.5_f64.log10()
+                        }
+                    });
+                }
+            }
+            Some(ret)
+        }
+        n if n_alleles == 2 => Some(dosage_scores(ref_cov, alt_covs[0], n, model)),
+        _ => None,
+    }
+}
+
+/// Calculates genotype scores for every alternate allele dosage (0 to `ploidy` copies)
+/// based on the coverage values for the reference and alternate alleles.
+///
+/// # Parameters
+/// - `ref_cov`: The coverage value for the reference allele as a floating-point number.
+/// - `alt_cov`: The coverage value for the alternate allele as a floating-point number.
+/// - `ploidy`: The number of alleles in the genotype.
+/// - `model`: The sample's expected allele-fractions. Its Ref and Hom fractions are the error
+///   floor and ceiling of zero and `ploidy` copies.
+///
+/// # Returns
+/// A vector of `ploidy + 1` log-probabilities where index `d` is the score of `d` alternate copies.
+pub fn dosage_scores(ref_cov: f64, alt_cov: f64, ploidy: u8, model: &AfModel) -> Vec<f64> {
+    let total = ref_cov + alt_cov;
+    let log_combo = log_choose(total, alt_cov);
+    let p_model = model.p_alt(total);
+
+    (0..=ploidy)
+        .map(|dosage| {
+            let p_alt = match dosage {
+                0 => p_model[0],
+                d if d == ploidy => p_model[2],
+                d => d as f64 / ploidy as f64,
+            };
+            log_combo + alt_cov * p_alt.log10() + ref_cov * (1.0 - p_alt).log10()
+        })
+        .collect()
+}
+
+/// Determines the most likely number of alternate allele copies for a genotype with `ploidy` alleles.
+///
+/// # Parameters
+/// - `ref_cov`: The coverage value for the reference allele as a floating-point number.
+/// - `alt_cov`: The coverage value for the alternate allele as a floating-point number.
+/// - `ploidy`: The number of alleles in the genotype.
+/// - `model`: The sample's expected allele-fractions.
+///
+/// # Returns
+/// The alternate allele dosage between 0 and `ploidy`. Zero coverage returns 0.
+pub fn dosage_genotyper(ref_cov: f64, alt_cov: f64, ploidy: u8, model: &AfModel) -> u8 {
+    if (ref_cov + alt_cov) == 0.0 {
+        return 0;
+    }
+    let ret = dosage_scores(ref_cov, alt_cov, ploidy, model)
+        .iter()
+        .enumerate()
+        .max_by_key(|&(_, &x)| OrderedFloat(x))
+        .map(|(i, _)| i as u8)
+        .unwrap_or(0);
+    debug!("{} {} -> dosage {}/{}", ref_cov, alt_cov, ret, ploidy);
+    ret
+}
+
+/// Summarizes an alternate allele dosage as a `GTstate`.
+pub fn dosage_state(dosage: u8, ploidy: u8) -> GTstate {
+    match dosage {
+        0 => GTstate::Ref,
+        d if d >= ploidy => GTstate::Hom,
+        _ => GTstate::Het,
+    }
+}
+
+/// Calculates genotype quality (GQ) and sample quality (SQ) over every alternate allele dosage.
+///
+/// # Returns
+/// A tuple containing two floating-point values:
+/// - The first value is the genotype quality (GQ).
+/// - The second value is the sample quality (SQ).
+pub fn dosage_quals(ref_cov: f64, alt_cov: f64, ploidy: u8, model: &AfModel) -> (f64, f64) {
+    scores_to_quals(dosage_scores(ref_cov, alt_cov, ploidy, model))
+}
+
 /// Helper function for genotype_scores
+/// Binomial coefficients are log10, matching the log10 probabilities they're summed with
 const FACTORIAL_LIMIT: usize = 100;
 lazy_static::lazy_static! {
     static ref LOG_FACTORIALS: Vec<f64> = {
         let mut log_factorials = vec![0.0; FACTORIAL_LIMIT + 1];
         let mut log_n_fact = 0.0;
         for (n, item) in log_factorials.iter_mut().enumerate().take(FACTORIAL_LIMIT + 1).skip(1) {
-            log_n_fact += (n as f64).ln();
+            log_n_fact += (n as f64).log10();
             *item = log_n_fact;
         }
         log_factorials
@@ -214,7 +392,7 @@ fn log_choose(n: f64, k: f64) -> f64 {
     }
     for d in 1..((k + 1.0) as i32) {
         r += n.log10();
-        r -= d as f64;
+        r -= (d as f64).log10();
         n -= 1.0;
     }
 
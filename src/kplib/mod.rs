@@ -1,19 +1,32 @@
+mod afmodel;
+pub use crate::kplib::afmodel::{load_af_models, write_af_models, AfFitter, AfModel};
+
 mod annotator;
-pub use crate::kplib::annotator::GenotypeAnno;
+pub use crate::kplib::annotator::{add_partial_evidence, GenotypeAnno, VariantAnno};
 
 mod bedparser;
 pub use crate::kplib::bedparser::BedParser;
 
 mod cli;
-pub use crate::kplib::cli::{Cli, Commands, GTArgs, IOParams, KDParams, KanpigParams, PlupArgs};
+pub use crate::kplib::cli::{
+    read_plup_header, Cli, Commands, GTArgs, IOParams, KDParams, KanpigParams, PlupArgs,
+    PlupMergeArgs, PlupStatsArgs, PlupViewArgs,
+};
 
 mod cluster;
+pub use crate::kplib::cluster::downsample_haplotypes;
+
+mod evidence;
+pub use crate::kplib::evidence::EvidenceWriter;
+
+mod haplotag;
+pub use crate::kplib::haplotag::{haplotag_records, HaplotagWriter};
 
 mod haplotype;
 pub use crate::kplib::haplotype::Haplotype;
 
 mod kmer;
-pub use crate::kplib::kmer::seq_to_kmer;
+pub use crate::kplib::kmer::{qual_seq_to_kmer, reverse_complement, seq_to_kmer};
 
 mod metrics;
 
@@ -23,14 +36,25 @@ pub use crate::kplib::pathscore::PathScore;
 mod pileup;
 pub use crate::kplib::pileup::{PileupVariant, ReadPileup};
 
+mod plupv2;
+pub use crate::kplib::plupv2::{is_plup_v2, plup_index_path, PlupReader, PlupWriter};
+
+mod plupio;
+pub use crate::kplib::plupio::{PlupFile, PlupOutput, CONTIG_END};
+
 mod ploidy;
 pub use crate::kplib::ploidy::{Ploidy, PloidyRegions};
 
 mod readparsers;
-pub use crate::kplib::readparsers::{BamParser, PlupParser, ReadParser};
+pub use crate::kplib::readparsers::{BamParser, MultiParser, PlupParser, ReadParser};
+
+mod readgroups;
+pub use crate::kplib::readgroups::ReadGroupFilter;
 
 mod regions;
-pub use crate::kplib::regions::{build_region_tree, Regions};
+pub use crate::kplib::regions::{
+    build_region_tree, merge_intervals, parse_region, Regions, TrCatalog,
+};
 
 mod traverse;
 pub use crate::kplib::traverse::brute_force_find_path;
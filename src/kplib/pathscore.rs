@@ -10,7 +10,10 @@ pub struct PathScore {
     pub seqsim: f32,
     pub coverage: Option<u64>,
     pub path: Vec<NodeIndex>,
-    pub full_target: bool, // Does this path use partial
+    pub full_target: bool,        // Does this path use partial
+    pub clipped: bool,            // Does this path use soft-clipped evidence
+    pub span: Option<(u64, u64)>, // Reference span of a partially spanning read
+    pub reads: Vec<String>,       // Names of the reads supporting the path
     pub ps: Option<u32>,
     pub hp: Option<u8>,
 }
@@ -46,6 +49,9 @@ impl Default for PathScore {
             seqsim: 0.0,
             coverage: None,
             full_target: false,
+            clipped: false,
+            span: None,
+            reads: vec![],
             ps: None,
             hp: None,
         }
@@ -62,6 +68,11 @@ impl PathScore {
         target: &Haplotype,
     ) -> Self {
         let mut path_k: Option<Vec<f32>> = None;
+        let path_inv: u64 = path
+            .iter()
+            .filter_map(|&node_index| graph.node_weight(node_index))
+            .map(|x| x.inv_span)
+            .sum();
         let mut best_path = PathScore::default();
         // Return the partials in order from all to least
         for hap_parts in targets {
@@ -69,7 +80,19 @@ impl PathScore {
                 continue;
             }
 
-            let sizesim = metrics::sizesim(path_size.unsigned_abs(), hap_parts.size.unsigned_abs());
+            // Clipped insertions only hold part of the inserted sequence
+            let clipped = hap_parts.clipped > 0;
+            let sizesim = if clipped && path_size.unsigned_abs() >= hap_parts.size.unsigned_abs() {
+                1.0
+            } else {
+                metrics::sizesim(path_size.unsigned_abs(), hap_parts.size.unsigned_abs())
+            };
+            // Inversions don't change the size, so the inverted spans must also be similar
+            let sizesim = if path_inv != 0 || hap_parts.inv_span != 0 {
+                sizesim.min(metrics::sizesim(path_inv, hap_parts.inv_span))
+            } else {
+                sizesim
+            };
 
             if sizesim < params.sizesim {
                 continue;
@@ -90,18 +113,26 @@ impl PathScore {
                 );
             }
 
-            let seqsim = metrics::seqsim(
-                path_k.as_ref().unwrap(),
-                &hap_parts.kfeat,
-                params.minkfreq as f32,
-            );
+            let seqsim = if clipped {
+                metrics::containsim(
+                    path_k.as_ref().unwrap(),
+                    &hap_parts.kfeat,
+                    params.minkfreq as f32,
+                )
+            } else {
+                metrics::seqsim(
+                    path_k.as_ref().unwrap(),
+                    &hap_parts.kfeat,
+                    params.minkfreq as f32,
+                )
+            };
 
             if seqsim < params.seqsim {
                 continue;
             }
 
-            let mut score =
-                ((seqsim + sizesim) / 2.0) - (params.fpenalty * hap_parts.partial as f32);
+            let mut score = ((seqsim + sizesim) / 2.0)
+                - (params.fpenalty * (hap_parts.partial + hap_parts.clipped) as f32);
 
             if params.squish {
                 score -= params.gpenalty * (path.len() - 1) as f32;
@@ -117,6 +148,9 @@ impl PathScore {
                     seqsim,
                     coverage: None,
                     full_target: hap_parts.partial == 0,
+                    clipped,
+                    span: target.span,
+                    reads: vec![],
                     ps: target.ps,
                     hp: target.hp,
                 };
@@ -1,19 +1,95 @@
 /// A pileup variant that's hashable / comparable
-use crate::kplib::Svtype;
+use crate::kplib::{metrics::overlaps, reverse_complement, Svtype};
 use rust_htslib::{bam::ext::BamRecordExtensions, bam::record::Aux, bam::Record};
 use std::{
     fmt,
     hash::{Hash, Hasher},
+    sync::Once,
 };
 
+static UNUSABLE_TAG: Once = Once::new();
+
+/// Integer value of a phasing tag. Strings (e.g. `H1` or `hap2`) use their trailing digits
+fn aux_integer(aux: &Aux) -> Option<i64> {
+    match *aux {
+        Aux::U8(value) => Some(value as i64),
+        Aux::U16(value) => Some(value as i64),
+        Aux::U32(value) => Some(value as i64),
+        Aux::I8(value) => Some(value as i64),
+        Aux::I16(value) => Some(value as i64),
+        Aux::I32(value) => Some(value as i64),
+        Aux::Char(value) => (value as char).to_digit(10).map(i64::from),
+        Aux::Float(value) if value.fract() == 0.0 => Some(value as i64),
+        Aux::Double(value) if value.fract() == 0.0 => Some(value as i64),
+        Aux::String(value) => {
+            let digits = value
+                .rfind(|c: char| !c.is_ascii_digit())
+                .map_or(value, |pos| &value[pos + 1..]);
+            digits.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Reads a phasing tag. Reads with values that can't be used are logged and left unphased
+fn phase_tag<T: TryFrom<i64>>(record: &Record, tag: &[u8]) -> Option<T> {
+    let aux = record.aux(tag).ok()?;
+    let value = aux_integer(&aux).and_then(|v| T::try_from(v).ok());
+    if value.is_none() {
+        let tag = String::from_utf8_lossy(tag);
+        let qname = String::from_utf8_lossy(record.qname());
+        UNUSABLE_TAG.call_once(|| {
+            warn!(
+                "Reads with unusable {} tags are unphased (e.g. {})",
+                tag, qname
+            )
+        });
+        debug!("Unusable {} tag on {}: {:?}", tag, qname, aux);
+    }
+    value
+}
+
 #[derive(Debug)]
 pub struct ReadPileup {
+    pub qname: String, // Empty when decoded from a plup without read info
     pub chrom: String,
     pub start: u64,
     pub end: u64,
     pub pileups: Vec<PileupVariant>,
     pub ps: Option<u32>,
     pub hp: Option<u8>,
+    pub mapq: Option<u8>,   // None when decoded from a plup without read info
+    pub flags: Option<u16>, // None when decoded from a plup without read info
+}
+
+/// An insertion or deletion operation of a read's CIGAR
+struct CigarIndel {
+    indel: Svtype,
+    position: usize,
+    len: usize,
+    read_offset: usize,
+}
+
+/// Groups indels of one type whose reference distance from the previous indel is within
+/// `merge_gap`. Aligners often fragment a large indel into several smaller ones a few bases apart.
+/// Without a `merge_gap`, every indel is its own group.
+fn merge_indels<'a>(indels: &[&'a CigarIndel], merge_gap: u64) -> Vec<Vec<&'a CigarIndel>> {
+    let mut groups: Vec<Vec<&CigarIndel>> = vec![];
+    let mut prev_end = 0;
+    for indel in indels {
+        match groups.last_mut() {
+            Some(group) if merge_gap > 0 && indel.position - prev_end <= merge_gap as usize => {
+                group.push(indel)
+            }
+            _ => groups.push(vec![indel]),
+        }
+        // Insertions don't take up reference
+        prev_end = match indel.indel {
+            Svtype::Del => indel.position + indel.len,
+            _ => indel.position,
+        };
+    }
+    groups
 }
 
 /// A struct representing a read and its pileups
@@ -24,6 +100,9 @@ impl ReadPileup {
     /// - `record`: The `Record` from which the pileup is constructed.
     /// - `sizemin`: The minimum size of variants to include in the pileup.
     /// - `sizemax`: The maximum size of variants to include in the pileup.
+    /// - `merge_gap`: The maximum reference distance between indels of the same type to merge (off=0).
+    /// - `hp_tag`: The aux tag holding the read's haplotag.
+    /// - `ps_tag`: The aux tag holding the read's phase set.
     ///
     /// # Returns
     /// - A `ReadPileup` instance containing the extracted variants that satisfy the size constraints.
@@ -34,58 +113,61 @@ impl ReadPileup {
     ///     - **Deletions (D)**: Captures the deletion if its length is within `[sizemin, sizemax]`.
     ///     - **Matches (M, X, =) and Soft-clips (S)**: Advances offsets without creating variants.
     ///     - **Hard-clips (H) and Pads (P)**: Ignores these operations.
+    /// - Indels of the same type within `merge_gap` of one another are merged before size filtering.
+    ///   Merged insertions concatenate their sequence and merged deletions sum their sizes.
     /// - Logs an error for any unexpected CIGAR operation.
+    /// - Phasing tags holding other integer types or strings (e.g. `H1`) are converted where
+    ///   possible. Otherwise, the read is unphased.
     ///
     /// # Example
-    /// ```rust
+    /// ```ignore
     /// let record = ...; // A valid BAM record
-    /// let pileup = ReadPileup::new(record, 10, 100);
+    /// let pileup = ReadPileup::new("chr1".to_string(), &record, 10, 100, 0, b"HP", b"PS");
     /// println!("{:?}", pileup);
     /// ```
-    pub fn new(chrom: String, record: &Record, sizemin: u32, sizemax: u32) -> Self {
+    pub fn new(
+        chrom: String,
+        record: &Record,
+        sizemin: u32,
+        sizemax: u32,
+        merge_gap: u64,
+        hp_tag: &[u8],
+        ps_tag: &[u8],
+    ) -> Self {
         let start = record.reference_start();
         let end = record.reference_end();
 
-        let mut pileups = Vec::<PileupVariant>::new();
+        let mut indels = Vec::<CigarIndel>::new();
         let mut read_offset = 0;
         let mut align_offset = start as usize - 1;
 
         for cigar in record.cigar().iter() {
+            let len = cigar.len() as usize;
             match cigar.char() {
-                'I' if sizemin <= cigar.len() && cigar.len() <= sizemax => {
-                    let sequence = record.seq().as_bytes()
-                        [read_offset..read_offset + cigar.len() as usize]
-                        .to_vec();
-
-                    pileups.push(PileupVariant::new(
-                        align_offset as u64,
-                        (align_offset + 1) as u64,
-                        Svtype::Ins,
-                        cigar.len() as i64,
-                        Some(sequence),
-                    ));
-
-                    read_offset += cigar.len() as usize;
+                'I' => {
+                    indels.push(CigarIndel {
+                        indel: Svtype::Ins,
+                        position: align_offset,
+                        len,
+                        read_offset,
+                    });
+                    read_offset += len;
                 }
-                'D' if sizemin <= cigar.len() && cigar.len() <= sizemax => {
-                    pileups.push(PileupVariant::new(
-                        align_offset as u64,
-                        (align_offset + (cigar.len() as usize)) as u64,
-                        Svtype::Del,
-                        -(cigar.len() as i64),
-                        None,
-                    ));
-                    align_offset += cigar.len() as usize;
+                'D' => {
+                    indels.push(CigarIndel {
+                        indel: Svtype::Del,
+                        position: align_offset,
+                        len,
+                        read_offset,
+                    });
+                    align_offset += len;
                 }
                 'M' | 'X' | '=' => {
-                    read_offset += cigar.len() as usize;
-                    align_offset += cigar.len() as usize;
+                    read_offset += len;
+                    align_offset += len;
                 }
-                'I' | 'S' => {
-                    read_offset += cigar.len() as usize;
-                }
-                'D' => {
-                    align_offset += cigar.len() as usize;
+                'S' => {
+                    read_offset += len;
                 }
                 // Handle 'H' and 'P' explicitly to ignore them
                 'H' | 'P' => {}
@@ -95,40 +177,377 @@ impl ReadPileup {
             }
         }
 
-        let ps = match record.aux(b"PS") {
-            Ok(Aux::U32(value)) => Some(value),
-            Ok(Aux::U16(value)) => Some(value as u32),
-            Ok(Aux::I32(value)) => Some(value as u32), // Convert signed to unsigned if necessary
-            Ok(Aux::I16(value)) => Some(value as u32),
-            Ok(Aux::U8(value)) => Some(value as u32),
-            Ok(Aux::I8(value)) => Some(value as u32),
-            Ok(other) => {
-                panic!("Unexpected type for PS tag: {:?}", other);
+        // Sequence is only decoded when the read has an insertion to keep
+        let mut sequence: Option<Vec<u8>> = None;
+        let mut pileups = Vec::<PileupVariant>::new();
+        for indel in [Svtype::Ins, Svtype::Del] {
+            let m_indels: Vec<&CigarIndel> = indels.iter().filter(|i| i.indel == indel).collect();
+            for group in merge_indels(&m_indels, merge_gap) {
+                let size: usize = group.iter().map(|i| i.len).sum();
+                if size < sizemin as usize || size > sizemax as usize {
+                    continue;
+                }
+                let position = group[0].position as u64;
+                let variant = match indel {
+                    Svtype::Ins => {
+                        let read_seq = sequence.get_or_insert_with(|| record.seq().as_bytes());
+                        let ins_seq = group
+                            .iter()
+                            .flat_map(|i| &read_seq[i.read_offset..i.read_offset + i.len])
+                            .copied()
+                            .collect();
+                        let mut variant = PileupVariant::new(
+                            position,
+                            position + 1,
+                            Svtype::Ins,
+                            size as i64,
+                            Some(ins_seq),
+                        );
+                        variant.quals = group
+                            .iter()
+                            .map(|i| read_quals(record, i.read_offset, i.read_offset + i.len))
+                            .collect::<Option<Vec<_>>>()
+                            .map(|quals| quals.concat());
+                        variant
+                    }
+                    _ => PileupVariant::new(
+                        position,
+                        position + size as u64,
+                        Svtype::Del,
+                        -(size as i64),
+                        None,
+                    ),
+                };
+                pileups.push(variant);
             }
-            Err(_) => None,
-        };
+        }
+        pileups.sort_by_key(|p| p.position);
 
-        let hp = match record.aux(b"HP") {
-            Ok(Aux::U8(value)) => Some(value),
-            Ok(Aux::U16(value)) => Some(value as u8),
-            Ok(Aux::U32(value)) => Some(value as u8),
-            Ok(Aux::I32(value)) => Some(value as u8), // Convert signed to unsigned if necessary
-            Ok(Aux::I16(value)) => Some(value as u8),
-            Ok(Aux::I8(value)) => Some(value as u8),
-            Ok(other) => {
-                panic!("Unexpected type for PS tag: {:?}", other);
-            }
-            Err(_) => None,
-        };
+        let ps = phase_tag(record, ps_tag);
+        // Haplotags are numbered from 1
+        let hp = phase_tag(record, hp_tag).filter(|&hp: &u8| hp > 0);
 
         Self {
+            qname: String::from_utf8_lossy(record.qname()).to_string(),
             chrom,
             start: start as u64,
             end: end as u64,
             pileups,
             ps,
             hp,
+            mapq: Some(record.mapq()),
+            flags: Some(record.flags()),
+        }
+    }
+
+    /// Drops the read's name, MAPQ and flags so they aren't written to a plup
+    pub fn clear_info(&mut self) {
+        self.qname.clear();
+        self.mapq = None;
+        self.flags = None;
+    }
+
+    /// Whether the read passes MAPQ and flag filters. Reads without info always pass
+    pub fn passes(&self, mapq: u8, mapflag: u16) -> bool {
+        self.mapq.is_none_or(|m| m >= mapq) && self.flags.is_none_or(|f| f & mapflag == 0)
+    }
+
+    /// '+' or '-' strand of the alignment when its flags are known
+    pub fn strand(&self) -> Option<char> {
+        self.flags.map(|f| if f & 0x10 == 0 { '+' } else { '-' })
+    }
+
+    /// Adds the SVs described by a read's split alignments (SA tag) to the pileup.
+    ///
+    /// # Parameters
+    /// - `record`: The primary alignment `Record` the pileup was constructed from.
+    /// - `sizemin`: The minimum size of variants to include in the pileup.
+    /// - `sizemax`: The maximum size of variants to include in the pileup.
+    /// - `mapq`: The minimum mapping quality of supplementary alignments to consider.
+    ///
+    /// # Function Details
+    /// - The primary and the supplementary alignments on the same chromosome are ordered by their
+    ///   position in the read. Each pair of neighboring alignments on the same strand can describe:
+    ///     - **Deletions**: The reference gap between the alignments is larger than the read gap.
+    ///     - **Insertions**: The read gap is larger than the reference gap. The unaligned read
+    ///       sequence is used as the inserted sequence when the primary isn't hard-clipped.
+    /// - An alignment on the opposite strand of both of its neighbors is an **Inversion** of the
+    ///   reference it aligns to.
+    /// - Reads spanning only one of an inversion's breakpoints switch strands once (e.g. a
+    ///   reverse-strand supplementary). The neighboring alignments' ends (forward first) or starts
+    ///   (reverse first) meet at the breakpoint and bound the **Inversion**.
+    /// - The pileup's start/end are extended to the alignments which describe a variant so the
+    ///   read can span neighborhoods larger than any one alignment.
+    /// - Supplementary records are ignored so a read's SVs are only added once.
+    pub fn add_splits(&mut self, record: &Record, sizemin: u32, sizemax: u32, mapq: u8) {
+        if record.is_supplementary() {
+            return;
+        }
+        let sa_tag = match record.aux(b"SA") {
+            Ok(Aux::String(value)) => value,
+            _ => return,
+        };
+
+        let Some(primary) = AlnSegment::new(
+            record.reference_start() as u64,
+            record.is_reverse(),
+            &record.cigar().to_string(),
+        ) else {
+            return;
+        };
+
+        let mut segments = vec![primary.clone()];
+        for sa in sa_tag.split(';').filter(|sa| !sa.is_empty()) {
+            let fields: Vec<&str> = sa.split(',').collect();
+            if fields.len() < 6 || fields[0] != self.chrom {
+                continue;
+            }
+            let (Ok(pos), Ok(sa_mapq)) = (fields[1].parse::<u64>(), fields[4].parse::<u8>()) else {
+                continue;
+            };
+            if sa_mapq < mapq || pos == 0 {
+                continue;
+            }
+            if let Some(seg) = AlnSegment::new(pos - 1, fields[2] == "-", fields[3]) {
+                segments.push(seg);
+            }
+        }
+
+        if segments.len() < 2 {
+            return;
+        }
+        segments.sort_by_key(|seg| seg.q_start);
+
+        // The read in its sequenced orientation. Hard-clipped reads don't have all of it
+        let sequence = record.seq().as_bytes();
+        let read_seq = match (sequence.len() as u64 == primary.read_len, primary.reverse) {
+            (true, true) => Some(reverse_complement(&sequence)),
+            (true, false) => Some(sequence),
+            (false, _) => None,
+        };
+
+        let in_size = |size: u64| sizemin as u64 <= size && size <= sizemax as u64;
+        let mut linked = vec![];
+
+        for pair in segments.windows(2) {
+            let (seg_a, seg_b) = (&pair[0], &pair[1]);
+            if seg_a.reverse != seg_b.reverse {
+                continue;
+            }
+            // Reference order follows the strand
+            let (left, right) = if seg_a.reverse {
+                (seg_b, seg_a)
+            } else {
+                (seg_a, seg_b)
+            };
+            let q_gap = seg_b.q_start as i64 - seg_a.q_end as i64;
+            let r_gap = right.ref_start as i64 - left.ref_end as i64;
+            let anchor = left.ref_end - 1;
+
+            if r_gap > 0 && q_gap < sizemin as i64 && in_size((r_gap - q_gap) as u64) {
+                let size = (r_gap - q_gap) as u64;
+                self.push_unique(PileupVariant::new(
+                    anchor,
+                    anchor + size,
+                    Svtype::Del,
+                    -(size as i64),
+                    None,
+                ));
+                linked.extend([left.clone(), right.clone()]);
+            } else if q_gap > 0 && r_gap.abs() < sizemin as i64 && in_size(q_gap as u64) {
+                let Some(read_seq) = &read_seq else {
+                    continue;
+                };
+                let mut ins_seq = read_seq[seg_a.q_end as usize..seg_b.q_start as usize].to_vec();
+                if seg_a.reverse {
+                    ins_seq = reverse_complement(&ins_seq);
+                }
+                self.push_unique(PileupVariant::new(
+                    anchor,
+                    anchor + 1,
+                    Svtype::Ins,
+                    q_gap,
+                    Some(ins_seq),
+                ));
+                linked.extend([left.clone(), right.clone()]);
+            }
+        }
+
+        for triple in segments.windows(3) {
+            let (flank_a, inv, flank_b) = (&triple[0], &triple[1], &triple[2]);
+            let span = inv.ref_end - inv.ref_start;
+            if flank_a.reverse == flank_b.reverse
+                && flank_a.reverse != inv.reverse
+                && inv.ref_start > 0
+                && in_size(span)
+            {
+                let anchor = inv.ref_start - 1;
+                self.push_unique(PileupVariant::new(
+                    anchor,
+                    anchor + span,
+                    Svtype::Inv,
+                    span as i64,
+                    None,
+                ));
+                linked.extend([flank_a.clone(), inv.clone(), flank_b.clone()]);
+            }
+        }
+
+        for pair in segments.windows(2) {
+            let (seg_a, seg_b) = (&pair[0], &pair[1]);
+            if seg_a.reverse == seg_b.reverse {
+                continue;
+            }
+            let (inv_start, inv_end) = if seg_a.reverse {
+                (
+                    seg_a.ref_start.min(seg_b.ref_start),
+                    seg_a.ref_start.max(seg_b.ref_start),
+                )
+            } else {
+                (
+                    seg_a.ref_end.min(seg_b.ref_end),
+                    seg_a.ref_end.max(seg_b.ref_end),
+                )
+            };
+            let span = inv_end - inv_start;
+            // Already found from both breakpoints
+            let known = self.pileups.iter().any(|p| {
+                p.indel == Svtype::Inv && overlaps(p.position + 1, p.end + 1, inv_start, inv_end)
+            });
+            if known || inv_start == 0 || !in_size(span) {
+                continue;
+            }
+            let anchor = inv_start - 1;
+            self.push_unique(PileupVariant::new(
+                anchor,
+                anchor + span,
+                Svtype::Inv,
+                span as i64,
+                None,
+            ));
+            linked.extend([seg_a.clone(), seg_b.clone()]);
+        }
+
+        for seg in linked {
+            self.start = self.start.min(seg.ref_start);
+            self.end = self.end.max(seg.ref_end);
+        }
+        // Variants are encoded relative to the start
+        for p in self.pileups.iter() {
+            self.start = self.start.min(p.position);
         }
+        self.pileups.sort_by_key(|p| p.position);
+    }
+
+    /// Adds a variant unless an equal one is already in the pileup
+    fn push_unique(&mut self, variant: PileupVariant) {
+        if !self.pileups.contains(&variant) {
+            self.pileups.push(variant);
+        }
+    }
+
+    /// Adds the soft-clipped sequence at either end of a read to the pileup as `clipped` insertions.
+    ///
+    /// # Parameters
+    /// - `record`: The alignment `Record` the pileup was constructed from.
+    /// - `sizemin`: The minimum size of clips to include in the pileup.
+    /// - `sizemax`: The maximum size of clips to include in the pileup.
+    ///
+    /// # Function Details
+    /// - Reads which end inside an insertion often soft-clip the inserted sequence instead of
+    ///   aligning it with an `I` operation. The clip is only part of the insertion, so the variant
+    ///   is flagged as `clipped`.
+    /// - A leading clip is placed at the read's first aligned base and a trailing clip at its last.
+    /// - Hard-clipped sequence isn't available and is ignored.
+    pub fn add_clips(&mut self, record: &Record, sizemin: u32, sizemax: u32) {
+        let cigar = record.cigar();
+        let start = record.reference_start() as u64;
+        let end = record.reference_end() as u64;
+        let in_size = |len: u32| sizemin <= len && len <= sizemax;
+        let sequence = record.seq().as_bytes();
+
+        let lead = cigar
+            .iter()
+            .take_while(|c| matches!(c.char(), 'S' | 'H'))
+            .find(|c| c.char() == 'S')
+            .map(|c| c.len());
+        if let Some(len) = lead.filter(|&len| in_size(len)) {
+            self.push_clip(
+                start,
+                sequence[..len as usize].to_vec(),
+                read_quals(record, 0, len as usize),
+            );
+        }
+
+        let trail = cigar
+            .iter()
+            .rev()
+            .take_while(|c| matches!(c.char(), 'S' | 'H'))
+            .find(|c| c.char() == 'S')
+            .map(|c| c.len());
+        if let Some(len) = trail.filter(|&len| in_size(len)) {
+            let clip_start = sequence.len() - len as usize;
+            self.push_clip(
+                end - 1,
+                sequence[clip_start..].to_vec(),
+                read_quals(record, clip_start, sequence.len()),
+            );
+        }
+        self.pileups.sort_by_key(|p| p.position);
+    }
+
+    fn push_clip(&mut self, position: u64, sequence: Vec<u8>, quals: Option<Vec<u8>>) {
+        let mut variant = PileupVariant::new(
+            position,
+            position + 1,
+            Svtype::Ins,
+            sequence.len() as i64,
+            Some(sequence),
+        );
+        variant.clipped = true;
+        variant.quals = quals;
+        self.push_unique(variant);
+    }
+
+    /// Checks if a read spans a window when its clipped insertions are considered.
+    ///
+    /// # Parameters
+    /// - `window_start`: The start of the window.
+    /// - `window_end`: The end of the window.
+    /// - `breakpoints`: The positions of insertions being genotyped.
+    /// - `clipdist`: The maximum distance of a clip from a breakpoint.
+    ///
+    /// # Returns
+    /// - `bool`: If the read fully spans the window or it spans up to an insertion from one side.
+    ///
+    /// # Function Details
+    /// - Clipped insertions further than `clipdist` from every breakpoint are removed.
+    /// - A read with a trailing clip spans the window if it starts before the window. A read with a
+    ///   leading clip spans the window if it ends after the window.
+    pub fn clip_spans(
+        &mut self,
+        window_start: u64,
+        window_end: u64,
+        breakpoints: &[u64],
+        clipdist: u64,
+    ) -> bool {
+        self.pileups.retain(|p| {
+            !p.clipped
+                || breakpoints
+                    .iter()
+                    .any(|&b| b.abs_diff(p.position) <= clipdist)
+        });
+
+        let starts_before = self.start < window_start;
+        let ends_after = self.end > window_end;
+        if starts_before && ends_after {
+            return true;
+        }
+
+        self.pileups.iter().filter(|p| p.clipped).any(|p| {
+            (p.position == self.start && ends_after)
+                || (p.position + 1 == self.end && starts_before)
+        })
     }
 
     /// Decodes a `ReadPileup` from a tab-delimited string, applying size constraints to the included variants.
@@ -146,13 +565,15 @@ impl ReadPileup {
     ///     - `chrom`: The chromosome name (ignored during decoding, set to 0).
     ///     - `start` and `end`: The start and end positions of the pileup.
     ///     - `pileups`: A comma-separated string of variants.
+    ///     - `ps` and `hp`: The phase set and haplotag, or `.` when absent.
+    ///     - `qname`, `mapq`, `strand` and `flags`: Optional read info. Six column lines have none.
     /// - Filters decoded variants based on size constraints.
     ///
     /// # TODO: Since parsing from an alignment record uses tid (i32) for chrom, the chromosome is
     /// never parsed from a string.
     ///
     /// # Example
-    /// ```rust
+    /// ```ignore
     /// let line = b"chr1\t1000\t1010\t.";
     /// let pileup = ReadPileup::decode(line, 10, 100);
     /// println!("{:?}", pileup);
@@ -190,14 +611,28 @@ impl ReadPileup {
             _ => Some(hp.parse().ok()?),
         };
 
+        // Strand is only written for readability. It's part of the flags
+        let (qname, mapq, flags) = match fields.next() {
+            Some(qname) => {
+                let mapq = fields.next()?.parse().ok()?;
+                let _strand = fields.next()?;
+                let flags = fields.next()?.parse().ok()?;
+                (qname.to_string(), Some(mapq), Some(flags))
+            }
+            None => (String::new(), None, None),
+        };
+
         // I use chrom 0 for the decode because new puts in tid
         Some(ReadPileup {
+            qname,
             chrom,
             start,
             end,
             pileups,
             ps,
             hp,
+            mapq,
+            flags,
         })
     }
 }
@@ -228,17 +663,93 @@ impl fmt::Display for ReadPileup {
             f,
             "{}\t{}\t{}\t{}\t{}\t{}",
             self.chrom, self.start, self.end, pstr, ps, hp
-        )
+        )?;
+
+        match (self.mapq, self.strand(), self.flags) {
+            (Some(mapq), Some(strand), Some(flags)) => {
+                write!(f, "\t{}\t{}\t{}\t{}", self.qname, mapq, strand, flags)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+/// One alignment of a read. Query coordinates are in the read's sequenced orientation
+#[derive(Clone, Debug)]
+struct AlnSegment {
+    ref_start: u64,
+    ref_end: u64,
+    q_start: u64,
+    q_end: u64,
+    read_len: u64,
+    reverse: bool,
+}
+
+impl AlnSegment {
+    /// Build a segment from its 0-based reference start, strand, and CIGAR string
+    fn new(ref_start: u64, reverse: bool, cigar: &str) -> Option<Self> {
+        let (mut lead_clip, mut trail_clip, mut q_len, mut r_len) = (0, 0, 0, 0);
+        let mut num: u64 = 0;
+        for c in cigar.chars() {
+            if let Some(d) = c.to_digit(10) {
+                num = num * 10 + d as u64;
+                continue;
+            }
+            match c {
+                'S' | 'H' if q_len == 0 && r_len == 0 => lead_clip += num,
+                'S' | 'H' => trail_clip += num,
+                'M' | '=' | 'X' => {
+                    q_len += num;
+                    r_len += num;
+                }
+                'I' => q_len += num,
+                'D' | 'N' => r_len += num,
+                'P' => {}
+                _ => return None,
+            }
+            num = 0;
+        }
+
+        if q_len == 0 || r_len == 0 {
+            return None;
+        }
+
+        // Reverse alignments' CIGAR is relative to the reverse complement
+        let q_start = if reverse { trail_clip } else { lead_clip };
+        Some(Self {
+            ref_start,
+            ref_end: ref_start + r_len,
+            q_start,
+            q_end: q_start + q_len,
+            read_len: lead_clip + q_len + trail_clip,
+            reverse,
+        })
     }
 }
+
 pub struct PileupVariant {
     pub position: u64,
     pub end: u64,
     pub indel: Svtype,
     pub size: i64,
     pub sequence: Option<Vec<u8>>,
+    pub clipped: bool,
+    pub quals: Option<Vec<u8>>, // Phred base qualities of an insertion's sequence
 }
 
+/// Base qualities of part of a read. None when the record doesn't have qualities
+fn read_quals(record: &Record, start: usize, end: usize) -> Option<Vec<u8>> {
+    record
+        .qual()
+        .get(start..end)
+        .filter(|quals| quals.first() != Some(&255))
+        .map(<[u8]>::to_vec)
+}
+
+/// Offset of plup encoded base qualities. Keeps them clear of the ',' and ':' delimiters
+const PLUP_QUAL_OFFSET: u8 = 64;
+/// Highest encodable base quality
+const PLUP_QUAL_MAX: u8 = b'~' - PLUP_QUAL_OFFSET;
+
 /// Provides information for an individual deletion or insertion with
 /// methods to create, decode, and encode structural variant information.
 impl PileupVariant {
@@ -247,15 +758,15 @@ impl PileupVariant {
     /// # Parameters
     /// - `position`: The start position of the variant.
     /// - `end`: The end position of the variant.
-    /// - `indel`: The type of the structural variant (`Svtype::Del` or `Svtype::Ins`).
-    /// - `size`: The size of the variant (positive for insertions, negative for deletions).
+    /// - `indel`: The type of the structural variant (`Svtype::Del`, `Svtype::Ins`, or `Svtype::Inv`).
+    /// - `size`: The size of the variant (positive for insertions, negative for deletions, span of inversions).
     /// - `sequence`: The sequence of the variant (only applicable for insertions).
     ///
     /// # Returns
-    /// A new `PileupVariant` instance with the specified properties.
+    /// A new `PileupVariant` instance with the specified properties. It isn't `clipped`.
     ///
     /// # Example
-    /// ```rust
+    /// ```ignore
     /// let variant = PileupVariant::new(1000, 1001, Svtype::Ins, 50, Some(vec![65, 67, 71, 84]));
     /// ```
     pub fn new(
@@ -271,13 +782,16 @@ impl PileupVariant {
             indel,
             size,
             sequence,
+            clipped: false,
+            quals: None,
         }
     }
 
     /// Decodes a string entry into a `PileupVariant`.
     ///
     /// # Parameters
-    /// - `entry`: A string slice representing a variant entry (e.g., `offset:size` for deletions or `offset:sequence` for insertions).
+    /// - `entry`: A string slice representing a variant entry (e.g., `offset:size` for deletions, `offset:sequence` for insertions, `offset:^sequence` for clipped insertions, or `offset:~size` for inversions).
+    ///   Insertions may have a trailing `:quals` of their base qualities.
     /// - `start`: The start position of the reference region to calculate the absolute position.
     ///
     /// # Returns
@@ -291,9 +805,15 @@ impl PileupVariant {
     /// - For insertions (`offset:sequence`):
     ///   - Parses `offset` and derives the sequence from the remaining string.
     ///   - Calculates the size based on the sequence length.
+    /// - For clipped insertions (`offset:^sequence`):
+    ///   - Parsed as an insertion which is `clipped`.
+    /// - For inversions (`offset:~size`):
+    ///   - Parses `offset` and the inverted span as the size.
+    /// - Insertion base qualities (`offset:sequence:quals`) are phred+64 encoded. Qualities which
+    ///   don't match the sequence's length are dropped.
     ///
     /// # Example
-    /// ```rust
+    /// ```ignore
     /// let variant = PileupVariant::decode("10:ACGT", 1000).unwrap();
     /// assert_eq!(variant.position, 1010);
     /// assert_eq!(variant.indel, Svtype::Ins);
@@ -304,6 +824,35 @@ impl PileupVariant {
         let offset = parts.next()?.parse::<u64>().ok()?;
         let m_pos = start + offset;
         let value = parts.next()?;
+        let quals = parts.next().map(|q| {
+            q.bytes()
+                .map(|b| b.saturating_sub(PLUP_QUAL_OFFSET))
+                .collect::<Vec<u8>>()
+        });
+
+        if let Some(span) = value.strip_prefix('~') {
+            let span = span.parse::<u64>().ok()?;
+            return Some(PileupVariant::new(
+                m_pos,
+                m_pos + span,
+                Svtype::Inv,
+                span as i64,
+                None,
+            ));
+        }
+
+        if let Some(seq) = value.strip_prefix('^') {
+            let mut variant = PileupVariant::new(
+                m_pos,
+                m_pos + 1,
+                Svtype::Ins,
+                seq.len() as i64,
+                Some(seq.as_bytes().to_vec()),
+            );
+            variant.clipped = true;
+            variant.quals = quals.filter(|q| q.len() == seq.len());
+            return Some(variant);
+        }
 
         let (end, svtype, size, seq) = match value.parse::<u64>() {
             Ok(size) => (m_pos + size, Svtype::Del, -(size as i64), None),
@@ -315,7 +864,11 @@ impl PileupVariant {
             ),
         };
 
-        Some(PileupVariant::new(m_pos, end, svtype, size, seq))
+        let mut variant = PileupVariant::new(m_pos, end, svtype, size, seq);
+        if variant.indel == Svtype::Ins {
+            variant.quals = quals.filter(|q| q.len() == value.len());
+        }
+        Some(variant)
     }
 
     /// Encodes a `PileupVariant` into a string representation.
@@ -327,36 +880,71 @@ impl PileupVariant {
     /// A string representation of the variant:
     /// - For deletions: `offset:size` (e.g., `10:50`).
     /// - For insertions: `offset:sequence` (e.g., `10:ACGT`).
+    /// - For clipped insertions: `offset:^sequence` (e.g., `10:^ACGT`).
+    /// - For inversions: `offset:~size` (e.g., `10:~5000`).
+    ///
+    /// Insertions with base qualities have them appended phred+64 encoded (e.g., `10:ACGT:^^J^`).
+    /// Qualities above 62 are capped.
     ///
     /// # Panics
-    /// - If the variant type is neither `Svtype::Del` nor `Svtype::Ins`.
+    /// - If the variant type is not `Svtype::Del`, `Svtype::Ins`, or `Svtype::Inv`.
     ///
     /// # Example
-    /// ```rust
+    /// ```ignore
     /// let variant = PileupVariant::new(1010, 1011, Svtype::Ins, 4, Some(vec![65, 67, 71, 84]));
     /// assert_eq!(variant.encode(1000), "10:ACGT");
     /// ```
     pub fn encode(&self, offset: u64) -> String {
         match self.indel {
             Svtype::Del => format!("{}:{}", self.position - offset, self.size.abs()),
-            Svtype::Ins => format!(
-                "{}:{}",
-                self.position - offset,
-                self.sequence
-                    .clone()
-                    .and_then(|seq| String::from_utf8(seq).ok())
-                    .unwrap()
-            ),
+            Svtype::Ins => {
+                let quals = self.quals.as_ref().map_or(String::new(), |quals| {
+                    let encoded: String = quals
+                        .iter()
+                        .map(|q| (q.min(&PLUP_QUAL_MAX) + PLUP_QUAL_OFFSET) as char)
+                        .collect();
+                    format!(":{}", encoded)
+                });
+                format!(
+                    "{}:{}{}{}",
+                    self.position - offset,
+                    if self.clipped { "^" } else { "" },
+                    self.sequence
+                        .clone()
+                        .and_then(|seq| String::from_utf8(seq).ok())
+                        .unwrap(),
+                    quals
+                )
+            }
+            Svtype::Inv => format!("{}:~{}", self.position - offset, self.size),
             _ => panic!("Unencodeable PileupVariant"),
         }
     }
+
+    /// Combines the base qualities of an identical pileup from another read by their per-base max
+    /// Reads without qualities don't lower the other's
+    pub fn merge_quals(&mut self, quals: Option<Vec<u8>>) {
+        self.quals = match (self.quals.take(), quals) {
+            (Some(mine), Some(theirs)) if mine.len() == theirs.len() => Some(
+                mine.iter()
+                    .zip(theirs.iter())
+                    .map(|(a, b)| *a.max(b))
+                    .collect(),
+            ),
+            (mine, theirs) => mine.or(theirs),
+        };
+    }
 }
 
 // Implement PartialEq trait for custom equality comparison
 impl PartialEq for PileupVariant {
     fn eq(&self, other: &Self) -> bool {
         // Compare position, size, and indel types
-        if self.position != other.position || self.size != other.size || self.indel != other.indel {
+        if self.position != other.position
+            || self.size != other.size
+            || self.indel != other.indel
+            || self.clipped != other.clipped
+        {
             return false;
         }
 
@@ -378,6 +966,7 @@ impl Hash for PileupVariant {
         self.position.hash(state);
         self.size.hash(state);
         self.indel.hash(state);
+        self.clipped.hash(state);
         if self.indel == Svtype::Ins {
             self.sequence.as_ref().unwrap().hash(state);
         }
@@ -395,7 +984,61 @@ impl std::fmt::Debug for PileupVariant {
             .field("size", &self.size)
             .field("indel", &self.indel)
             .field("sequence", &seq)
+            .field("clipped", &self.clipped)
             // Exclude kfeat from the debug output
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quals_roundtrip_phred64() {
+        let mut variant = PileupVariant::new(1010, 1011, Svtype::Ins, 4, Some(b"ACGT".to_vec()));
+        variant.quals = Some(vec![0, 30, 62, 90]);
+        // Qualities above 62 are capped
+        assert_eq!(variant.encode(1000), "10:ACGT:@^~~");
+        let decoded = PileupVariant::decode("10:ACGT:@^~~", 1000).unwrap();
+        assert_eq!(decoded.sequence.as_deref(), Some(&b"ACGT"[..]));
+        assert_eq!(decoded.quals, Some(vec![0, 30, 62, 62]));
+
+        // Qualities which don't match the sequence are dropped
+        let decoded = PileupVariant::decode("10:ACGT:@@", 1000).unwrap();
+        assert_eq!(decoded.quals, None);
+        let decoded = PileupVariant::decode("10:ACGT", 1000).unwrap();
+        assert_eq!(decoded.quals, None);
+    }
+
+    #[test]
+    fn read_quals_roundtrip() {
+        for line in [
+            "chr1\t1000\t2000\t10:ACGT:@^~J,20:^GG:JJ,50:120\t.\t.",
+            "chr1\t1000\t2000\t10:ACGT,20:^GG,50:120,60:~700\t4\t2",
+        ] {
+            let read = ReadPileup::decode(line.as_bytes(), 1, 10000).unwrap();
+            assert_eq!(read.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn read_info_roundtrip() {
+        let line = "chr1\t1000\t2000\t10:120\t.\t.\tread/1\t60\t-\t16";
+        let mut read = ReadPileup::decode(line.as_bytes(), 1, 10000).unwrap();
+        assert_eq!(read.to_string(), line);
+        assert_eq!(read.qname, "read/1");
+        assert_eq!(
+            (read.mapq, read.flags, read.strand()),
+            (Some(60), Some(16), Some('-'))
+        );
+        assert!(read.passes(60, 0x4));
+        assert!(!read.passes(61, 0x4));
+        assert!(!read.passes(0, 0x10));
+
+        // Without info, reads are six columns and pass any filter
+        read.clear_info();
+        assert_eq!(read.to_string(), "chr1\t1000\t2000\t10:120\t.\t.");
+        assert!(read.passes(255, 0xffff));
+    }
+}
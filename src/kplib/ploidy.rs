@@ -1,25 +1,25 @@
-use crate::kplib::cluster::{diploid_haplotypes, haploid_haplotypes};
-use crate::kplib::{BedParser, Haplotype, KDParams};
+use crate::kplib::cluster::{diploid_haplotypes, haploid_haplotypes, polyploid_haplotypes};
+use crate::kplib::{AfModel, BedParser, Haplotype, KDParams};
 use rust_lapper::{Interval, Lapper};
 use std::{collections::HashMap, str::FromStr};
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Ploidy {
     Zero,
     Haploid,
     Diploid,
-    Polyploid,
+    Polyploid(u8),
     Unset,
 }
 
 impl FromStr for Ploidy {
     type Err = ();
 
+    /// Ploidies are 0 to 255. Anything else can't be represented
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.trim() {
-            "0" => Ok(Ploidy::Zero),
-            "1" => Ok(Ploidy::Haploid),
-            _ => Ok(Ploidy::Unset),
+        match s.trim().parse::<u8>() {
+            Ok(value) => Ok(Ploidy::from_value(value as u64)),
+            Err(_) => Err(()),
         }
     }
 }
@@ -30,22 +30,32 @@ impl Ploidy {
             0 => Ploidy::Zero,
             1 => Ploidy::Haploid,
             2 => Ploidy::Diploid,
-            3 => Ploidy::Polyploid,
+            3..=255 => Ploidy::Polyploid(value as u8),
             _ => Ploidy::Unset,
         }
     }
 
+    /// Number of alleles in a genotype. Unset is treated as diploid
+    pub fn value(&self) -> u64 {
+        match self {
+            Ploidy::Zero => 0,
+            Ploidy::Haploid => 1,
+            Ploidy::Diploid | Ploidy::Unset => 2,
+            Ploidy::Polyploid(n) => *n as u64,
+        }
+    }
+
     pub fn cluster(
         &self,
         haps: Vec<Haplotype>,
         coverage: u64,
         params: &KDParams,
+        model: &AfModel,
     ) -> Vec<Haplotype> {
         match self {
             Ploidy::Haploid => haploid_haplotypes(haps, coverage, params),
-            _ => diploid_haplotypes(haps, coverage, params),
-            // and then eventually this could allow a --ploidy flag to branch to
-            // polyploid_haplotypes
+            Ploidy::Polyploid(n) => polyploid_haplotypes(haps, coverage, *n, params, model),
+            _ => diploid_haplotypes(haps, coverage, params, model),
         }
     }
 }
@@ -56,13 +66,17 @@ pub type IvLookup = HashMap<String, Lapper<u64, u64>>;
 #[derive(Clone)]
 pub struct PloidyRegions {
     intervals: IvLookup,
+    // Ploidy of everything outside the intervals
+    default: Ploidy,
 }
 
 impl PloidyRegions {
-    pub fn new(path: &Option<std::path::PathBuf>) -> Self {
+    pub fn new(path: &Option<std::path::PathBuf>, default: u8) -> Self {
+        let default = Ploidy::from_value(default as u64);
         if path.is_none() {
             return PloidyRegions {
                 intervals: IvLookup::new(),
+                default,
             };
         }
 
@@ -70,14 +84,26 @@ impl PloidyRegions {
         let mut hold_intv = HashMap::<String, Vec<(u64, u64, u64)>>::new();
         for entry in m_parser.parse().into_iter() {
             if entry.data.is_none() {
-                error!("No ploidy (0 or 1) in entry {:?}", entry);
+                error!("No ploidy (e.g. 0 or 1) in entry {:?}", entry);
                 std::process::exit(1);
             }
-            let m_ploy: Ploidy = entry.data.unwrap()[0].parse().unwrap();
-            hold_intv
-                .entry(entry.chrom)
-                .or_default()
-                .push((entry.start, entry.end, m_ploy as u64));
+            let data = entry.data.unwrap();
+            let Ok(m_ploy) = data[0].parse::<Ploidy>() else {
+                error!(
+                    "Invalid ploidy {:?} (expected 0 to 255) in --ploidy-bed line: {}\t{}\t{}\t{}",
+                    data[0],
+                    entry.chrom,
+                    entry.start,
+                    entry.end,
+                    data.join("\t")
+                );
+                std::process::exit(1);
+            };
+            hold_intv.entry(entry.chrom).or_default().push((
+                entry.start,
+                entry.end,
+                m_ploy.value(),
+            ));
         }
         // Convert the original HashMap into a new HashMap<String, Lapper>
         let intervals: IvLookup = hold_intv
@@ -95,17 +121,31 @@ impl PloidyRegions {
             })
             .collect();
 
-        Self { intervals }
+        Self { intervals, default }
     }
 
     pub fn get_ploidy(&self, chrom: &String, start: u64) -> Ploidy {
         if let Some(lapper) = self.intervals.get(chrom) {
             match lapper.find(start, start + 1).next() {
                 Some(i) => Ploidy::from_value(i.val),
-                None => Ploidy::Unset,
+                None => self.default,
             }
         } else {
-            Ploidy::Unset
+            self.default
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unrepresentable_ploidy_is_an_error() {
+        assert_eq!("4".parse::<Ploidy>(), Ok(Ploidy::Polyploid(4)));
+        assert_eq!(" 1\n".parse::<Ploidy>(), Ok(Ploidy::Haploid));
+        assert!("256".parse::<Ploidy>().is_err());
+        assert!("-1".parse::<Ploidy>().is_err());
+        assert!("two".parse::<Ploidy>().is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::kplib::{is_plup_v2, PlupArgs, PlupReader, PlupWriter, ReadPileup};
+use rust_htslib::{
+    bgzf, htslib,
+    tbx::{self, Read as TbxRead},
+};
+use std::{
+    ffi::CString,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// End position which covers the whole of any contig
+pub const CONTIG_END: u64 = 1 << 40;
+
+/// Text plups (v1) are tabix indexed. Binary plups (v2) have their own index
+pub enum PlupFile {
+    Text(tbx::Reader),
+    Binary(Box<PlupReader>),
+}
+
+impl PlupFile {
+    /// Opens a plup. Its version is detected
+    pub fn new(path: &Path) -> Self {
+        if is_plup_v2(path) {
+            PlupFile::Binary(Box::new(
+                PlupReader::new(path).expect("Failed to open binary plup"),
+            ))
+        } else {
+            PlupFile::Text(tbx::Reader::from_path(path).expect("Failed to open TBX file"))
+        }
+    }
+
+    /// Names of the plup's contigs. Text plups only know the contigs which have reads
+    pub fn contigs(&self) -> Vec<String> {
+        match self {
+            PlupFile::Text(tbx) => tbx.seqnames(),
+            PlupFile::Binary(reader) => reader.contigs().to_vec(),
+        }
+    }
+
+    /// Reads overlapping a region with their pileups within the size boundaries
+    pub fn fetch(
+        &mut self,
+        chrom: &str,
+        start: u64,
+        end: u64,
+        sizemin: u32,
+        sizemax: u32,
+    ) -> Vec<ReadPileup> {
+        self.fetch_iter(chrom, start, end, sizemin, sizemax)
+            .collect()
+    }
+
+    /// Reads overlapping a region, read one at a time, with their pileups within the size boundaries
+    pub fn fetch_iter(
+        &mut self,
+        chrom: &str,
+        start: u64,
+        end: u64,
+        sizemin: u32,
+        sizemax: u32,
+    ) -> Box<dyn Iterator<Item = ReadPileup> + '_> {
+        match self {
+            PlupFile::Text(tbx) => {
+                let tid = match tbx.tid(chrom) {
+                    Ok(t) => t,
+                    Err(_) => return Box::new(std::iter::empty()),
+                };
+                tbx.fetch(tid, start, end)
+                    .expect("Could not fetch region from TBX");
+                Box::new(
+                    tbx.records()
+                        .filter_map(Result::ok)
+                        .filter_map(move |line| ReadPileup::decode(&line, sizemin, sizemax)),
+                )
+            }
+            PlupFile::Binary(reader) => {
+                let (sizemin, sizemax) = (sizemin as u64, sizemax as u64);
+                let reads = reader
+                    .fetch_iter(chrom, start, end)
+                    .expect("Could not fetch region from binary plup");
+                Box::new(reads.map(move |read| {
+                    let mut read = read.expect("Could not read binary plup");
+                    read.pileups.retain(|p| {
+                        p.size.unsigned_abs() >= sizemin && p.size.unsigned_abs() <= sizemax
+                    });
+                    read
+                }))
+            }
+        }
+    }
+}
+
+/// Text (v1) or binary (v2) plup output
+pub enum PlupOutput {
+    Text(Box<dyn Write>),
+    Binary(PlupWriter),
+}
+
+impl PlupOutput {
+    /// Output described by the header's `output` and `binary`. The header is written first
+    pub fn new(header: &PlupArgs, contigs: &[String]) -> Self {
+        if header.binary {
+            let path = header.output.as_ref().expect("--binary requires --output");
+            let writer =
+                PlupWriter::new(path, header, contigs).expect("Error Creating Output File");
+            return PlupOutput::Binary(writer);
+        }
+
+        let mut writer: Box<dyn Write> = match header.output {
+            Some(ref path) => {
+                let m_page = page_size::get() * 1000;
+                let file = bgzf::Writer::from_path(path).expect("Error Creating Output File");
+                Box::new(BufWriter::with_capacity(m_page, file))
+            }
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        let serialized = serde_json::to_string(header).expect("Error writing header");
+        let prefixed = format!("# {}\n", serialized);
+        let _ = writer.write_all(prefixed.as_bytes());
+        PlupOutput::Text(writer)
+    }
+
+    pub fn write(&mut self, read: &ReadPileup) {
+        match self {
+            PlupOutput::Text(writer) => {
+                writeln!(writer, "{}", read).expect("Error writing to output file")
+            }
+            PlupOutput::Binary(writer) => writer.write(read).expect("Error writing to output file"),
+        }
+    }
+
+    /// Writes a chromosome's reads sorted by their start. Returns how many were written
+    pub fn write_chrom(&mut self, mut reads: Vec<ReadPileup>) -> u64 {
+        reads.sort_by_key(|read| read.start);
+        for read in reads.iter() {
+            self.write(read);
+        }
+        reads.len() as u64
+    }
+
+    /// Closes the output and builds its index
+    pub fn finish(self, output: &Option<PathBuf>) {
+        match self {
+            PlupOutput::Text(writer) => {
+                // Output must be closed before it's indexed
+                drop(writer);
+                if let Some(path) = output {
+                    index_plup(path);
+                }
+            }
+            PlupOutput::Binary(writer) => writer.finish().expect("Error writing to output file"),
+        }
+    }
+}
+
+/// Builds the tabix index of a bgzipped plup
+fn index_plup(path: &Path) {
+    let c_path = CString::new(path.to_string_lossy().as_bytes()).expect("Invalid --output path");
+    let ret = unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &htslib::tbx_conf_bed) };
+    if ret < 0 {
+        error!("Unable to index {}", path.display());
+        std::process::exit(1);
+    }
+}
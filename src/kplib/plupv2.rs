@@ -0,0 +1,609 @@
+//! Binary plup (v2)
+//!
+//! A BGZF compressed stream of:
+//! - Header: `PLUP`, the format version byte, then the creation `PlupArgs` JSON and the contig
+//!   names, each as a varint length followed by its bytes.
+//! - Reads sorted by position: a varint length of the rest of the read, then varints of the contig
+//!   index, start, span, a byte of flags (PS/HP/read info present), the PS and HP when present,
+//!   the read info (name, MAPQ byte, and varint SAM flags) when present, and the number of
+//!   pileups.
+//! - Pileups: a byte of its type, a varint offset from the read's start, then either a varint size
+//!   (deletions, inversions) or a varint length and the 2-bit packed sequence (insertions) with its
+//!   base qualities when present. Bases other than ACGT (e.g. N) follow the packed sequence as a
+//!   varint count and, for each, a varint gap from the previous one and the base.
+//!
+//! The region index (`.pli`) holds the virtual position of the first read overlapping each 16kbp
+//! bin of every contig.
+use crate::kplib::{PileupVariant, PlupArgs, ReadPileup, Svtype};
+use noodles_bgzf::{self as bgzf, VirtualPosition};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const PLUP_MAGIC: &[u8; 4] = b"PLUP";
+const PLUP_VERSION: u8 = 2;
+const INDEX_MAGIC: &[u8; 4] = b"PLI\x02";
+const BIN_SHIFT: u64 = 14;
+
+// Read flags
+const HAS_PS: u8 = 0b01;
+const HAS_HP: u8 = 0b10;
+const HAS_INFO: u8 = 0b100;
+
+// Pileup types
+const P_DEL: u8 = 0;
+const P_INS: u8 = 1;
+const P_INV: u8 = 2;
+const P_CLIPPED: u8 = 0b0100;
+const P_QUALS: u8 = 0b1000;
+const P_OTHER_BASES: u8 = 0b10000;
+
+/// Path of a binary plup's region index
+pub fn plup_index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".pli");
+    PathBuf::from(index)
+}
+
+/// Checks if a file starts with the binary plup header
+pub fn is_plup_v2(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut reader = bgzf::Reader::new(file);
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic).is_ok() && &magic[..4] == PLUP_MAGIC && magic[4] == PLUP_VERSION
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad varint"));
+        }
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_varint(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; read_varint(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Packs a sequence into 2 bits per base. Bases other than ACGT are packed as A and restored from
+/// `other_bases`
+fn pack_sequence(sequence: &[u8]) -> Vec<u8> {
+    sequence
+        .chunks(4)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0u8, |acc, (i, nuc)| {
+                let code = match nuc {
+                    b'C' => 1,
+                    b'G' => 2,
+                    b'T' => 3,
+                    _ => 0,
+                };
+                acc | (code << (i * 2))
+            })
+        })
+        .collect()
+}
+
+fn unpack_sequence(packed: &[u8], len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| match (packed[i / 4] >> ((i % 4) * 2)) & 0b11 {
+            0 => b'A',
+            1 => b'C',
+            2 => b'G',
+            _ => b'T',
+        })
+        .collect()
+}
+
+/// Positions and values of the bases which can't be 2-bit packed
+fn other_bases(sequence: &[u8]) -> Vec<(usize, u8)> {
+    sequence
+        .iter()
+        .enumerate()
+        .filter(|(_, nuc)| !matches!(nuc, b'A' | b'C' | b'G' | b'T'))
+        .map(|(i, nuc)| (i, *nuc))
+        .collect()
+}
+
+fn encode_pileup(buf: &mut Vec<u8>, p: &PileupVariant, start: u64) -> io::Result<()> {
+    let mut kind = match p.indel {
+        Svtype::Del => P_DEL,
+        Svtype::Ins => P_INS,
+        Svtype::Inv => P_INV,
+        _ => panic!("Unencodeable PileupVariant"),
+    };
+    if p.clipped {
+        kind |= P_CLIPPED;
+    }
+    if p.quals.is_some() {
+        kind |= P_QUALS;
+    }
+    let others = p.sequence.as_deref().map(other_bases).unwrap_or_default();
+    if !others.is_empty() {
+        kind |= P_OTHER_BASES;
+    }
+    buf.push(kind);
+    write_varint(buf, p.position - start)?;
+    match p.indel {
+        Svtype::Ins => {
+            let sequence = p.sequence.as_ref().expect("Insertions have a sequence");
+            write_varint(buf, sequence.len() as u64)?;
+            buf.extend(pack_sequence(sequence));
+            if !others.is_empty() {
+                write_varint(buf, others.len() as u64)?;
+                let mut prev = 0;
+                for (pos, nuc) in others {
+                    write_varint(buf, (pos - prev) as u64)?;
+                    buf.push(nuc);
+                    prev = pos;
+                }
+            }
+            if let Some(quals) = &p.quals {
+                buf.extend(quals);
+            }
+        }
+        _ => write_varint(buf, p.size.unsigned_abs())?,
+    }
+    Ok(())
+}
+
+fn decode_pileup<R: Read>(reader: &mut R, start: u64) -> io::Result<PileupVariant> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    let kind = kind[0];
+    let position = start + read_varint(reader)?;
+    let value = read_varint(reader)?;
+
+    let variant = match kind & 0b11 {
+        P_DEL => PileupVariant::new(
+            position,
+            position + value,
+            Svtype::Del,
+            -(value as i64),
+            None,
+        ),
+        P_INV => PileupVariant::new(position, position + value, Svtype::Inv, value as i64, None),
+        _ => {
+            let len = value as usize;
+            let mut packed = vec![0u8; len.div_ceil(4)];
+            reader.read_exact(&mut packed)?;
+            let mut sequence = unpack_sequence(&packed, len);
+            if kind & P_OTHER_BASES != 0 {
+                let mut pos = 0;
+                for _ in 0..read_varint(reader)? {
+                    pos += read_varint(reader)? as usize;
+                    let mut nuc = [0u8; 1];
+                    reader.read_exact(&mut nuc)?;
+                    *sequence.get_mut(pos).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Base beyond insertion")
+                    })? = nuc[0];
+                }
+            }
+            let mut variant = PileupVariant::new(
+                position,
+                position + 1,
+                Svtype::Ins,
+                len as i64,
+                Some(sequence),
+            );
+            variant.clipped = kind & P_CLIPPED != 0;
+            if kind & P_QUALS != 0 {
+                let mut quals = vec![0u8; len];
+                reader.read_exact(&mut quals)?;
+                variant.quals = Some(quals);
+            }
+            variant
+        }
+    };
+    Ok(variant)
+}
+
+/// Writes reads, sorted by position, to a binary plup and its region index
+pub struct PlupWriter {
+    writer: bgzf::Writer<BufWriter<File>>,
+    path: PathBuf,
+    tids: HashMap<String, usize>,
+    bins: Vec<Vec<u64>>, // Virtual position of the first read overlapping each bin. 0 is unset
+}
+
+impl PlupWriter {
+    pub fn new(path: &Path, params: &PlupArgs, contigs: &[String]) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = bgzf::Writer::new(file);
+        writer.write_all(PLUP_MAGIC)?;
+        writer.write_all(&[PLUP_VERSION])?;
+        let header = serde_json::to_string(params).map_err(io::Error::other)?;
+        write_bytes(&mut writer, header.as_bytes())?;
+        write_varint(&mut writer, contigs.len() as u64)?;
+        for contig in contigs {
+            write_bytes(&mut writer, contig.as_bytes())?;
+        }
+
+        Ok(Self {
+            writer,
+            path: path.to_path_buf(),
+            tids: contigs
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.clone(), i))
+                .collect(),
+            bins: vec![vec![]; contigs.len()],
+        })
+    }
+
+    pub fn write(&mut self, read: &ReadPileup) -> io::Result<()> {
+        let tid = *self
+            .tids
+            .get(&read.chrom)
+            .ok_or_else(|| io::Error::other(format!("Unknown contig {}", read.chrom)))?;
+
+        let pos = u64::from(self.writer.virtual_position());
+        let bins = &mut self.bins[tid];
+        let last_bin = (read.end.max(read.start + 1) - 1) >> BIN_SHIFT;
+        if bins.len() <= last_bin as usize {
+            bins.resize(last_bin as usize + 1, 0);
+        }
+        for bin in &mut bins[(read.start >> BIN_SHIFT) as usize..=last_bin as usize] {
+            if *bin == 0 {
+                *bin = pos;
+            }
+        }
+
+        let mut buf = Vec::with_capacity(64);
+        write_varint(&mut buf, tid as u64)?;
+        write_varint(&mut buf, read.start)?;
+        write_varint(&mut buf, read.end - read.start)?;
+        let mut flags = 0;
+        if read.ps.is_some() {
+            flags |= HAS_PS;
+        }
+        if read.hp.is_some() {
+            flags |= HAS_HP;
+        }
+        let info = read.mapq.zip(read.flags);
+        if info.is_some() {
+            flags |= HAS_INFO;
+        }
+        buf.push(flags);
+        if let Some(ps) = read.ps {
+            write_varint(&mut buf, ps as u64)?;
+        }
+        if let Some(hp) = read.hp {
+            buf.push(hp);
+        }
+        if let Some((mapq, sam_flags)) = info {
+            write_bytes(&mut buf, read.qname.as_bytes())?;
+            buf.push(mapq);
+            write_varint(&mut buf, sam_flags as u64)?;
+        }
+        write_varint(&mut buf, read.pileups.len() as u64)?;
+        for p in read.pileups.iter() {
+            encode_pileup(&mut buf, p, read.start)?;
+        }
+
+        write_bytes(&mut self.writer, &buf)
+    }
+
+    /// Closes the plup and writes its region index
+    pub fn finish(self) -> io::Result<()> {
+        self.writer.finish()?.flush()?;
+
+        let mut index = BufWriter::new(File::create(plup_index_path(&self.path))?);
+        index.write_all(INDEX_MAGIC)?;
+        write_varint(&mut index, self.bins.len() as u64)?;
+        for bins in self.bins.iter() {
+            write_varint(&mut index, bins.len() as u64)?;
+            for pos in bins {
+                write_varint(&mut index, *pos)?;
+            }
+        }
+        index.flush()
+    }
+}
+
+/// Reads a binary plup's header and fetches reads by region with its index
+pub struct PlupReader {
+    reader: bgzf::Reader<File>,
+    pub params: PlupArgs,
+    contigs: Vec<String>,
+    tids: HashMap<String, usize>,
+    bins: Vec<Vec<u64>>,
+}
+
+impl PlupReader {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut reader = bgzf::Reader::new(File::open(path)?);
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if &magic[..4] != PLUP_MAGIC || magic[4] != PLUP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a binary plup",
+            ));
+        }
+        let params = serde_json::from_slice(&read_bytes(&mut reader)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let n_contigs = read_varint(&mut reader)?;
+        let contigs = (0..n_contigs)
+            .map(|_| read_bytes(&mut reader).map(|name| String::from_utf8_lossy(&name).to_string()))
+            .collect::<io::Result<Vec<String>>>()?;
+
+        let mut index = BufReader::new(File::open(plup_index_path(path))?);
+        let mut magic = [0u8; 4];
+        index.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a binary plup index",
+            ));
+        }
+        let bins = (0..read_varint(&mut index)?)
+            .map(|_| {
+                (0..read_varint(&mut index)?)
+                    .map(|_| read_varint(&mut index))
+                    .collect::<io::Result<Vec<u64>>>()
+            })
+            .collect::<io::Result<Vec<Vec<u64>>>>()?;
+
+        Ok(Self {
+            reader,
+            params,
+            tids: contigs
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.clone(), i))
+                .collect(),
+            contigs,
+            bins,
+        })
+    }
+
+    /// Names of the contigs reads may be on, in the order they're written
+    pub fn contigs(&self) -> &[String] {
+        &self.contigs
+    }
+
+    /// The next read in the plup. None at the end of the file
+    pub fn read_next(&mut self) -> io::Result<Option<ReadPileup>> {
+        let len = match read_varint(&mut self.reader) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut buf = vec![0u8; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        let mut buf = &buf[..];
+
+        let tid = read_varint(&mut buf)? as usize;
+        let start = read_varint(&mut buf)?;
+        let end = start + read_varint(&mut buf)?;
+        let mut flags = [0u8; 1];
+        buf.read_exact(&mut flags)?;
+        let ps = match flags[0] & HAS_PS {
+            0 => None,
+            _ => Some(read_varint(&mut buf)? as u32),
+        };
+        let hp = match flags[0] & HAS_HP {
+            0 => None,
+            _ => {
+                let mut hp = [0u8; 1];
+                buf.read_exact(&mut hp)?;
+                Some(hp[0])
+            }
+        };
+        let (qname, mapq, sam_flags) = match flags[0] & HAS_INFO {
+            0 => (String::new(), None, None),
+            _ => {
+                let qname = String::from_utf8_lossy(&read_bytes(&mut buf)?).to_string();
+                let mut mapq = [0u8; 1];
+                buf.read_exact(&mut mapq)?;
+                let sam_flags = read_varint(&mut buf)? as u16;
+                (qname, Some(mapq[0]), Some(sam_flags))
+            }
+        };
+        let n_pileups = read_varint(&mut buf)?;
+        let pileups = (0..n_pileups)
+            .map(|_| decode_pileup(&mut buf, start))
+            .collect::<io::Result<Vec<PileupVariant>>>()?;
+
+        let chrom =
+            self.contigs.get(tid).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Unknown contig index")
+            })?;
+        Ok(Some(ReadPileup {
+            qname,
+            chrom,
+            start,
+            end,
+            pileups,
+            ps,
+            hp,
+            mapq,
+            flags: sam_flags,
+        }))
+    }
+
+    /// Reads overlapping a region, read one at a time
+    pub fn fetch_iter(
+        &mut self,
+        chrom: &str,
+        start: u64,
+        end: u64,
+    ) -> io::Result<impl Iterator<Item = io::Result<ReadPileup>> + '_> {
+        let first_pos = self.tids.get(chrom).and_then(|&tid| {
+            let bins = &self.bins[tid];
+            let first = (start >> BIN_SHIFT) as usize;
+            let last = ((end.max(start + 1) - 1) >> BIN_SHIFT) as usize;
+            // Reads are sorted, so the first read overlapping the region is at or after its first bin
+            bins.iter()
+                .take(last + 1)
+                .skip(first)
+                .find(|pos| **pos != 0)
+                .copied()
+        });
+        if let Some(pos) = first_pos {
+            self.reader.seek(VirtualPosition::from(pos))?;
+        }
+
+        let chrom = chrom.to_string();
+        let mut done = first_pos.is_none();
+        Ok(std::iter::from_fn(move || {
+            while !done {
+                match self.read_next() {
+                    Ok(Some(read)) if read.chrom != chrom || read.start >= end => done = true,
+                    Ok(Some(read)) if read.end > start => return Some(Ok(read)),
+                    Ok(Some(_)) => {}
+                    Ok(None) => done = true,
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            None
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Writes v1 lines' reads to a binary plup
+    fn write_plup(name: &str, lines: &[&str]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kanpig_{}_{}.plup", name, std::process::id()));
+        let params = PlupArgs::parse_from(["plup", "--bam", "reads.bam"]);
+        let contigs = ["chr1".to_string(), "chr2".to_string()];
+        let mut writer = PlupWriter::new(&path, &params, &contigs).unwrap();
+        for line in lines {
+            let read = ReadPileup::decode(line.as_bytes(), 1, 100_000).unwrap();
+            writer.write(&read).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn remove_plup(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(plup_index_path(path)).unwrap();
+    }
+
+    /// v1 lines of the reads in a binary plup
+    fn read_plup(path: &Path) -> Vec<String> {
+        let mut reader = PlupReader::new(path).unwrap();
+        let mut ret = vec![];
+        while let Some(read) = reader.read_next().unwrap() {
+            ret.push(read.to_string());
+        }
+        ret
+    }
+
+    /// v1 lines of the reads a binary plup fetches for a region
+    fn fetch_plup(path: &Path, chrom: &str, start: u64, end: u64) -> Vec<String> {
+        let mut reader = PlupReader::new(path).unwrap();
+        reader
+            .fetch_iter(chrom, start, end)
+            .unwrap()
+            .map(|read| read.unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn v1_v2_v1_keeps_other_bases() {
+        let lines = ["chr1\t1000\t5000\t10:ACNGTN,20:nnACGT,50:120\t.\t."];
+        let path = write_plup("other_bases", &lines);
+        assert_eq!(read_plup(&path), lines);
+        remove_plup(&path);
+    }
+
+    #[test]
+    fn v2_records_roundtrip() {
+        let lines = [
+            "chr1\t100\t900\t.\t.\t.",
+            "chr1\t1000\t9000\t10:120,200:ACGTACGT,300:^TTGCA,500:~2000\t7\t1",
+            "chr1\t20000\t21000\t5:GGGG\t.\t2",
+            "chr2\t5\t600\t1:50\t300000\t.",
+        ];
+        let path = write_plup("records", &lines);
+        assert_eq!(read_plup(&path), lines);
+        remove_plup(&path);
+    }
+
+    #[test]
+    fn v2_quals_roundtrip() {
+        let lines = [
+            "chr1\t1000\t9000\t10:ACGT:@^~J,300:^TTGCA:JJJJJ,400:AC\t.\t.",
+            "chr1\t2000\t9000\t10:ACNT:@@@@\t.\t.",
+        ];
+        let path = write_plup("quals", &lines);
+        assert_eq!(read_plup(&path), lines);
+        remove_plup(&path);
+    }
+
+    #[test]
+    fn v2_read_info_roundtrip() {
+        // Reads with and without info can be mixed
+        let lines = [
+            "chr1\t1000\t9000\t10:120\t7\t1\tm64011_190830/1/ccs\t60\t+\t0",
+            "chr1\t1000\t9000\t.\t.\t.",
+            "chr1\t2000\t9000\t10:ACGT\t.\t2\tread2\t0\t-\t2064",
+        ];
+        let path = write_plup("read_info", &lines);
+        assert_eq!(read_plup(&path), lines);
+        remove_plup(&path);
+    }
+
+    #[test]
+    fn v2_index_finds_reads_spanning_bins() {
+        // The long read starts in bin 0 and ends in bin 3, the short reads are in bins 1 and 4
+        let long = "chr1\t1000\t60000\t100:80\t.\t.";
+        let short = "chr1\t20000\t20500\t.\t.\t.";
+        let late = "chr1\t70000\t70500\t.\t.\t.";
+        let other = "chr2\t40000\t41000\t.\t.\t.";
+        let path = write_plup("index", &[long, short, late, other]);
+
+        assert_eq!(fetch_plup(&path, "chr1", 1500, 1600), [long]);
+        assert_eq!(fetch_plup(&path, "chr1", 20100, 20200), [long, short]);
+        assert_eq!(fetch_plup(&path, "chr1", 50000, 50010), [long]);
+        assert_eq!(fetch_plup(&path, "chr1", 59999, 70001), [long, late]);
+        assert!(fetch_plup(&path, "chr1", 60000, 69000).is_empty());
+        assert!(fetch_plup(&path, "chr1", 200000, 300000).is_empty());
+        assert_eq!(fetch_plup(&path, "chr2", 0, 50000), [other]);
+        assert!(fetch_plup(&path, "chr3", 0, 50000).is_empty());
+        remove_plup(&path);
+    }
+}
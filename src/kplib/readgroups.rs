@@ -0,0 +1,55 @@
+use rust_htslib::bam::{self, record::Aux};
+use std::collections::HashSet;
+
+/// Selects reads by their read group (RG tag) or the sample (SM) of their read group
+/// Without either selector, every read is kept
+#[derive(Clone, Debug, Default)]
+pub struct ReadGroupFilter {
+    groups: Option<HashSet<String>>,
+}
+
+impl ReadGroupFilter {
+    /// Finds the header's read groups matching both selectors
+    pub fn new(
+        header: &bam::HeaderView,
+        read_group: &Option<String>,
+        rg_sample: &Option<String>,
+    ) -> Self {
+        if read_group.is_none() && rg_sample.is_none() {
+            return Self::default();
+        }
+
+        let groups: HashSet<String> = bam::Header::from_template(header)
+            .to_hashmap()
+            .remove("RG")
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|rg| {
+                read_group
+                    .as_ref()
+                    .is_none_or(|id| rg.get("ID") == Some(id))
+                    && rg_sample.as_ref().is_none_or(|sm| rg.get("SM") == Some(sm))
+            })
+            .filter_map(|mut rg| rg.remove("ID"))
+            .collect();
+
+        if groups.is_empty() {
+            warn!("No read groups match --read-group/--rg-sample. No reads will be used");
+        }
+
+        Self {
+            groups: Some(groups),
+        }
+    }
+
+    /// Reads without an RG tag are only kept when there's no selector
+    pub fn keep(&self, record: &bam::Record) -> bool {
+        match &self.groups {
+            None => true,
+            Some(groups) => match record.aux(b"RG") {
+                Ok(Aux::String(rg)) => groups.contains(rg),
+                _ => false,
+            },
+        }
+    }
+}
@@ -1,24 +1,46 @@
-use crate::kplib::{seq_to_kmer, Haplotype, KDParams, PileupVariant, ReadPileup, Svtype};
-use indexmap::{IndexMap, IndexSet};
-use rust_htslib::faidx;
-use rust_htslib::{
-    bam::ext::BamRecordExtensions,
-    bam::{self, IndexedReader, Read as BamRead},
-    tbx::{self, Read as TbxRead},
+use crate::kplib::{
+    qual_seq_to_kmer, reverse_complement, seq_to_kmer, Haplotype, KDParams, PileupVariant,
+    PlupFile, ReadGroupFilter, ReadPileup, Svtype,
 };
+use indexmap::{set::MutableValues, IndexMap, IndexSet};
+use rust_htslib::bam::{self, ext::BamRecordExtensions, IndexedReader, Read as BamRead};
+use rust_htslib::faidx;
 use std::path::PathBuf;
 
 pub type ReadsMap = IndexMap<usize, Vec<usize>>;
 pub type PileupSet = IndexSet<PileupVariant>;
-type HPMap = IndexMap<usize, Option<u8>>;
+// Read name, HP tag and, for reads which don't span the window, the read's span
+type ReadInfoMap = IndexMap<usize, (String, Option<u8>, Option<(u64, u64)>)>;
 pub trait ReadParser {
-    fn find_pileups(&mut self, chrom: &str, start: u64, end: u64) -> (Vec<Haplotype>, u64);
+    /// Piles up reads spanning `window_start` to `window_end`
+    /// `breakpoints` are the positions of insertions which soft-clips can support
+    fn find_pileups(
+        &mut self,
+        chrom: &str,
+        window_start: u64,
+        window_end: u64,
+        breakpoints: &[u64],
+    ) -> (Vec<Haplotype>, u64);
+
+    /// Alignments of the reads spanning the last `find_pileups` window, when they're kept
+    fn take_records(&mut self) -> Vec<bam::Record> {
+        vec![]
+    }
+
+    /// Names of the reads spanning the last `find_pileups` window without any pileups in it
+    /// Empty unless the parser was created to keep them
+    fn take_ref_reads(&mut self) -> Vec<String>;
 }
 
 pub struct BamParser {
     bam: IndexedReader,
     reference: faidx::Reader,
     params: KDParams,
+    read_groups: ReadGroupFilter,
+    // Spanning reads of the last window. Only kept for haplotagging
+    records: Option<Vec<bam::Record>>,
+    // Names of the last window's spanning reads without pileups. Only kept when needed
+    ref_reads: Option<Vec<String>>,
 }
 
 impl BamParser {
@@ -27,31 +49,49 @@ impl BamParser {
         ref_name: PathBuf,
         reference: faidx::Reader,
         params: KDParams,
+        keep_records: bool,
+        keep_ref_reads: bool,
     ) -> Self {
         let mut bam = IndexedReader::from_path(bam_name).unwrap();
         let _ = bam.set_reference(ref_name.clone());
+        let read_groups = ReadGroupFilter::new(bam.header(), &params.read_group, &params.rg_sample);
         Self {
             bam,
             reference,
             params,
+            read_groups,
+            records: keep_records.then(Vec::new),
+            ref_reads: keep_ref_reads.then(Vec::new),
         }
     }
 }
 
 impl ReadParser for BamParser {
     /// Returns all unique haplotypes over a region
-    fn find_pileups(&mut self, chrom: &str, start: u64, end: u64) -> (Vec<Haplotype>, u64) {
-        // We pileup a little outside the region for variants
-        let window_start = start.saturating_sub(self.params.neighdist);
-        let window_end = end + self.params.neighdist;
-
+    fn find_pileups(
+        &mut self,
+        chrom: &str,
+        window_start: u64,
+        window_end: u64,
+        breakpoints: &[u64],
+    ) -> (Vec<Haplotype>, u64) {
         if let Err(e) = self.bam.fetch((&chrom, window_start, window_end)) {
-            panic!("Unable to fetch bam {}:{}-{}\n{:?}", chrom, start, end, e)
+            panic!(
+                "Unable to fetch bam {}:{}-{}\n{:?}",
+                chrom, window_start, window_end, e
+            )
         };
 
+        if let Some(records) = self.records.as_mut() {
+            records.clear();
+        }
+        if let Some(ref_reads) = self.ref_reads.as_mut() {
+            ref_reads.clear();
+        }
+
         // track the changes made by each read
         let mut reads = ReadsMap::new();
-        let mut hps = HPMap::new();
+        let mut read_info = ReadInfoMap::new();
         let mut ps = None;
         let mut p_variants = PileupSet::new();
         let mut coverage = 0;
@@ -62,30 +102,76 @@ impl ReadParser for BamParser {
             if !record.seq().is_empty()
                 && record.mapq() >= self.params.mapq
                 && (record.flags() & self.params.mapflag) == 0
-                && (record.reference_start() as u64) < window_start
-                && (record.reference_end() as u64) > window_end
+                && self.read_groups.keep(&record)
             {
-                coverage += 1;
+                // Only split alignments, clips and partial reads use the non-spanning reads
+                let spans_window = (record.reference_start() as u64) < window_start
+                    && (record.reference_end() as u64) > window_end;
+                let keep_partial =
+                    self.params.splits || self.params.clips || self.params.partial_reads;
+                if !spans_window && !keep_partial {
+                    continue;
+                }
                 let mut read = ReadPileup::new(
                     chrom.to_string(),
                     &record,
                     self.params.sizemin,
                     self.params.sizemax,
+                    self.params.merge_gap,
+                    self.params.hp_tag.as_bytes(),
+                    self.params.ps_tag.as_bytes(),
                 );
+                if self.params.splits {
+                    read.add_splits(
+                        &record,
+                        self.params.sizemin,
+                        self.params.sizemax,
+                        self.params.mapq,
+                    );
+                }
+                let spans = if self.params.clips {
+                    read.add_clips(&record, self.params.sizemin, self.params.sizemax);
+                    read.clip_spans(window_start, window_end, breakpoints, self.params.clipdist)
+                } else {
+                    // Split alignments can extend a read to span the window
+                    read.start < window_start && read.end > window_end
+                };
+                if spans {
+                    coverage += 1;
+                    if let Some(records) = self.records.as_mut() {
+                        records.push(record.clone());
+                    }
+                } else if self.params.partial_reads {
+                    // Reference reads need a haplotype to be counted
+                    reads.entry(qname).or_default();
+                    read_info.insert(
+                        qname,
+                        (read.qname.clone(), read.hp, Some((read.start, read.end))),
+                    );
+                } else {
+                    continue;
+                }
 
                 if ps.is_none() && read.ps.is_some() {
                     ps = read.ps;
                 }
 
                 if !read.pileups.is_empty() {
-                    hps.entry(qname).or_insert(read.hp);
+                    read_info
+                        .entry(qname)
+                        .or_insert_with(|| (read.qname.clone(), read.hp, None));
                 }
                 for m_var in read.pileups.drain(..) {
                     if m_var.position >= window_start && m_var.position <= window_end {
-                        let (p_idx, _) = p_variants.insert_full(m_var);
+                        let p_idx = insert_pileup(&mut p_variants, m_var);
                         reads.entry(qname).or_default().push(p_idx);
                     }
                 }
+                if let Some(ref_reads) = self.ref_reads.as_mut() {
+                    if !reads.contains_key(&qname) {
+                        ref_reads.push(read.qname);
+                    }
+                }
                 qname += 1;
             }
         }
@@ -97,71 +183,126 @@ impl ReadParser for BamParser {
                 p_variants,
                 &self.reference,
                 &self.params,
-                hps,
+                read_info,
                 ps,
             ),
             coverage,
         )
     }
+
+    fn take_records(&mut self) -> Vec<bam::Record> {
+        self.records
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn take_ref_reads(&mut self) -> Vec<String> {
+        self.ref_reads
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
 }
 
 pub struct PlupParser {
-    tbx: tbx::Reader,
+    source: PlupFile,
     reference: faidx::Reader,
     params: KDParams,
+    ref_reads: Option<Vec<String>>,
 }
 
 impl PlupParser {
-    /// Creates a new `PlupReader` for a given file path.
-    pub fn new(file_path: PathBuf, reference: faidx::Reader, params: KDParams) -> Self {
-        let tbx = tbx::Reader::from_path(&file_path).expect("Failed to open TBX file");
+    /// Creates a new `PlupReader` for a given file path. The plup's version is detected
+    pub fn new(
+        file_path: PathBuf,
+        reference: faidx::Reader,
+        params: KDParams,
+        keep_ref_reads: bool,
+    ) -> Self {
+        let source = PlupFile::new(&file_path);
         Self {
-            tbx,
+            source,
             reference,
             params,
+            ref_reads: keep_ref_reads.then(Vec::new),
         }
     }
+
+    /// Reads overlapping a window with their pileups within the size boundaries
+    /// Reads with info (plup --read-info) are also filtered by mapq and mapflag
+    fn fetch(&mut self, chrom: &str, window_start: u64, window_end: u64) -> Vec<ReadPileup> {
+        let mut reads = self.source.fetch(
+            chrom,
+            window_start,
+            window_end,
+            self.params.sizemin,
+            self.params.sizemax,
+        );
+        reads.retain(|read| read.passes(self.params.mapq, self.params.mapflag));
+        reads
+    }
 }
 
 impl ReadParser for PlupParser {
     /// Fetch and parse pileups within a specified genomic interval.
     /// Returns the set of haplotypes
-    fn find_pileups(&mut self, chrom: &str, start: u64, end: u64) -> (Vec<Haplotype>, u64) {
-        let window_start = start.saturating_sub(self.params.neighdist);
-        let window_end = end + self.params.neighdist;
-
-        let tid = match self.tbx.tid(chrom) {
-            Ok(t) => t,
-            Err(_) => return (vec![], 0),
-        };
-        self.tbx
-            .fetch(tid, window_start, window_end)
-            .expect("Could not fetch region from TBX");
-
+    fn find_pileups(
+        &mut self,
+        chrom: &str,
+        window_start: u64,
+        window_end: u64,
+        breakpoints: &[u64],
+    ) -> (Vec<Haplotype>, u64) {
         let mut reads = ReadsMap::new();
-        let mut hps = HPMap::new();
+        let mut read_info = ReadInfoMap::new();
         let mut ps = None;
         let mut p_variants = PileupSet::new();
         let mut coverage = 0;
+        if let Some(ref_reads) = self.ref_reads.as_mut() {
+            ref_reads.clear();
+        }
 
-        for (qname, line) in self.tbx.records().filter_map(Result::ok).enumerate() {
-            if let Some(mut read) =
-                ReadPileup::decode(&line, self.params.sizemin, self.params.sizemax)
-            {
-                if read.start < window_start && read.end > window_end {
-                    coverage += 1;
-                    if ps.is_none() && read.ps.is_some() {
-                        ps = read.ps;
-                    }
-                    if !read.pileups.is_empty() {
-                        hps.entry(qname).or_insert(read.hp);
-                    }
-                    for m_var in read.pileups.drain(..) {
-                        if m_var.position >= window_start && m_var.position <= window_end {
-                            let (p_idx, _) = p_variants.insert_full(m_var);
-                            reads.entry(qname).or_default().push(p_idx);
-                        }
-                    }
+        for (qname, mut read) in self
+            .fetch(chrom, window_start, window_end)
+            .into_iter()
+            .enumerate()
+        {
+            let spans = if self.params.clips {
+                read.clip_spans(window_start, window_end, breakpoints, self.params.clipdist)
+            } else {
+                read.pileups.retain(|p| !p.clipped);
+                read.start < window_start && read.end > window_end
+            };
+            if spans {
+                coverage += 1;
+            } else if self.params.partial_reads {
+                reads.entry(qname).or_default();
+                read_info.insert(
+                    qname,
+                    (read.qname.clone(), read.hp, Some((read.start, read.end))),
+                );
+            } else {
+                continue;
+            }
+
+            if ps.is_none() && read.ps.is_some() {
+                ps = read.ps;
+            }
+            if !read.pileups.is_empty() {
+                read_info
+                    .entry(qname)
+                    .or_insert_with(|| (read.qname.clone(), read.hp, None));
+            }
+            for m_var in read.pileups.drain(..) {
+                if m_var.position >= window_start && m_var.position <= window_end {
+                    let p_idx = insert_pileup(&mut p_variants, m_var);
+                    reads.entry(qname).or_default().push(p_idx);
+                }
+            }
+            if let Some(ref_reads) = self.ref_reads.as_mut() {
+                if !reads.contains_key(&qname) {
+                    ref_reads.push(read.qname);
                 }
             }
         }
@@ -173,12 +314,75 @@ impl ReadParser for PlupParser {
                 p_variants,
                 &self.reference,
                 &self.params,
-                hps,
+                read_info,
                 ps,
             ),
             coverage,
         )
     }
+
+    fn take_ref_reads(&mut self) -> Vec<String> {
+        self.ref_reads
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+/// Adds a read's pileup to the set and returns its index
+/// Pileups equal to one already in the set (their quals aren't compared) merge their quals into it
+fn insert_pileup(p_variants: &mut PileupSet, m_var: PileupVariant) -> usize {
+    if let Some((p_idx, existing)) = p_variants.get_full_mut2(&m_var) {
+        existing.merge_quals(m_var.quals);
+        return p_idx;
+    }
+    p_variants.insert_full(m_var).0
+}
+
+/// Reads of one sample spread across multiple files (e.g. per-flowcell BAMs)
+pub struct MultiParser {
+    parsers: Vec<Box<dyn ReadParser>>,
+}
+
+impl MultiParser {
+    pub fn new(parsers: Vec<Box<dyn ReadParser>>) -> Self {
+        Self { parsers }
+    }
+}
+
+impl ReadParser for MultiParser {
+    /// Every file's haplotypes and the sum of their coverage
+    fn find_pileups(
+        &mut self,
+        chrom: &str,
+        window_start: u64,
+        window_end: u64,
+        breakpoints: &[u64],
+    ) -> (Vec<Haplotype>, u64) {
+        let mut ret = vec![];
+        let mut coverage = 0;
+        for parser in self.parsers.iter_mut() {
+            let (haps, m_cov) = parser.find_pileups(chrom, window_start, window_end, breakpoints);
+            ret.extend(haps);
+            coverage += m_cov;
+        }
+        ret.sort_by(|a, b| b.cmp(a));
+        (ret, coverage)
+    }
+
+    fn take_ref_reads(&mut self) -> Vec<String> {
+        self.parsers
+            .iter_mut()
+            .flat_map(|parser| parser.take_ref_reads())
+            .collect()
+    }
+
+    fn take_records(&mut self) -> Vec<bam::Record> {
+        self.parsers
+            .iter_mut()
+            .flat_map(|parser| parser.take_records())
+            .collect()
+    }
 }
 
 /// Converts a set of pileups into haplotypes by grouping and deduplicating reads based on pileup combinations.
@@ -211,7 +415,7 @@ impl ReadParser for PlupParser {
 /// - Panics if the reference genome fetch fails for deletions.
 ///
 /// # Example
-/// ```rust
+/// ```ignore
 /// use faidx::Reader;
 /// use std::collections::HashMap;
 ///
@@ -232,13 +436,31 @@ fn pileups_to_haps(
     mut plups: PileupSet,
     reference: &faidx::Reader,
     params: &KDParams,
-    hps: HPMap,      // HP tags per-read
-    ps: Option<u32>, // The window's PS tag
+    mut read_info: ReadInfoMap, // Names, HP tags and partial spans per-read
+    ps: Option<u32>,            // The window's PS tag
 ) -> Vec<Haplotype> {
     let mut hap_parts = Vec::<Haplotype>::with_capacity(plups.len());
     let mut ret = Vec::<Haplotype>::with_capacity(reads.len());
 
     while let Some(mut p) = plups.pop() {
+        // Inversions change the kmers of the reference they span without changing its size
+        if p.indel == Svtype::Inv {
+            let ref_seq = reference
+                .fetch_seq(chrom, p.position as usize + 1, p.end as usize)
+                .unwrap()
+                .to_vec();
+            let mut kfeat = seq_to_kmer(
+                &reverse_complement(&ref_seq),
+                params.kmer,
+                false,
+                params.maxhom,
+            );
+            let ref_kfeat = seq_to_kmer(&ref_seq, params.kmer, false, params.maxhom);
+            kfeat.iter_mut().zip(ref_kfeat).for_each(|(x, y)| *x -= y);
+            hap_parts.push(Haplotype::new(kfeat, 0, 1, 1, None, None).invert(p.size as u64));
+            continue;
+        }
+
         // Need to fill in deleted sequence
         let sequence = match p.indel {
             Svtype::Del => reference
@@ -252,19 +474,22 @@ fn pileups_to_haps(
             _ => panic!("Unknown Svtype"),
         };
 
-        let n_hap = Haplotype::new(
-            seq_to_kmer(
+        let kfeat = match p.quals.take() {
+            Some(quals) if params.minbq > 0 => {
+                qual_seq_to_kmer(&sequence, &quals, params.kmer, params.maxhom, params.minbq)
+            }
+            _ => seq_to_kmer(
                 &sequence,
                 params.kmer,
                 p.indel == Svtype::Del,
                 params.maxhom,
             ),
-            p.size,
-            1,
-            1,
-            None,
-            None,
-        );
+        };
+        let clipped = p.clipped;
+        let mut n_hap = Haplotype::new(kfeat, p.size, 1, 1, None, None);
+        if clipped {
+            n_hap = n_hap.clip();
+        }
         hap_parts.push(n_hap);
     }
 
@@ -272,7 +497,12 @@ fn pileups_to_haps(
     for (read_idx, read) in reads.into_iter() {
         let mut cur_hap = Haplotype::blank(params.kmer, 1);
         cur_hap.ps = ps;
-        cur_hap.hp = *hps.get(&read_idx).expect("hp populated with reads");
+        let (name, hp, span) = read_info
+            .swap_remove(&read_idx)
+            .expect("read_info populated with reads");
+        cur_hap.hp = hp;
+        cur_hap.span = span;
+        cur_hap.reads = vec![name];
         for p in read {
             cur_hap.add(&hap_parts[hap_parts.len() - p - 1]);
         }
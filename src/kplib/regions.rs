@@ -1,8 +1,12 @@
 use indexmap::IndexMap;
 use noodles_vcf::header::record::value::{map::contig::Contig, Map};
+use rust_lapper::Lapper;
 use std::collections::{HashMap, VecDeque};
 
-use crate::kplib::BedParser;
+use crate::kplib::{
+    ploidy::{Iv, IvLookup},
+    BedParser, CONTIG_END,
+};
 
 pub type ContigMap = IndexMap<String, Map<Contig>>;
 pub type Regions = HashMap<String, VecDeque<(u64, u64)>>;
@@ -63,3 +67,84 @@ pub fn build_region_tree(
 
     ret
 }
+
+/// Parses a `chrom` or `chrom:start-end` (1-based, inclusive) region into the chromosome and its
+/// 0-based, half-open coordinates. A chromosome on its own spans to `CONTIG_END`
+pub fn parse_region(region: &str) -> Option<(String, u64, u64)> {
+    let span = region.rsplit_once(':').and_then(|(chrom, span)| {
+        let (start, end) = span.split_once('-')?;
+        let start: u64 = start.replace(',', "").parse().ok()?;
+        let end: u64 = end.replace(',', "").parse().ok()?;
+        Some((chrom, start, end))
+    });
+    match span {
+        Some((chrom, start, end)) if start >= 1 && start <= end && !chrom.is_empty() => {
+            Some((chrom.to_string(), start - 1, end))
+        }
+        Some(_) => None,
+        None if !region.is_empty() => Some((region.to_string(), 0, CONTIG_END)),
+        None => None,
+    }
+}
+
+/// Sorts a contig's intervals and merges those which overlap or touch
+pub fn merge_intervals(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Tandem repeat boundaries from a --tr-catalog bed
+#[derive(Clone, Default)]
+pub struct TrCatalog {
+    intervals: IvLookup,
+}
+
+impl TrCatalog {
+    pub fn new(path: &Option<std::path::PathBuf>) -> Self {
+        let Some(path) = path else {
+            return TrCatalog::default();
+        };
+
+        let mut hold_intv = HashMap::<String, Vec<Iv>>::new();
+        for entry in BedParser::new(path).parse().into_iter() {
+            hold_intv.entry(entry.chrom).or_default().push(Iv {
+                start: entry.start,
+                stop: entry.end,
+                val: 0,
+            });
+        }
+
+        let intervals: IvLookup = hold_intv
+            .into_iter()
+            .map(|(key, ivs)| (key, Lapper::new(ivs)))
+            .collect();
+        TrCatalog { intervals }
+    }
+
+    /// Extend a span to the boundaries of every repeat it overlaps.
+    /// Repeats which overlap those repeats are also included.
+    /// Returns None if the span doesn't overlap a repeat
+    pub fn span(&self, chrom: &str, start: u64, end: u64) -> Option<(u64, u64)> {
+        let lapper = self.intervals.get(chrom)?;
+        let (mut m_start, mut m_end) = (start, end.max(start + 1));
+        lapper.find(m_start, m_end).next()?;
+        loop {
+            let (n_start, n_end) = lapper
+                .find(m_start, m_end)
+                .fold((m_start, m_end), |(s, e), iv| {
+                    (s.min(iv.start), e.max(iv.stop))
+                });
+            if (n_start, n_end) == (m_start, m_end) {
+                return Some((m_start, m_end));
+            }
+            (m_start, m_end) = (n_start, n_end);
+        }
+    }
+}
@@ -70,7 +70,12 @@ pub fn brute_force_find_path(
                 ));
                 npaths += 1;
             } else {
-                let nsize = cur_path.size + graph.node_weight(next_node).unwrap().size;
+                let node = graph.node_weight(next_node).unwrap();
+                // Partially spanning reads can only support the variants they cover
+                if !target.covers(node.start, node.end) {
+                    continue;
+                }
+                let nsize = cur_path.size + node.size;
                 let mut npath = cur_path.path.clone();
                 npath.push(next_node);
                 stack.push(PathNodeState {
@@ -99,6 +104,10 @@ pub fn get_one_to_one(
 ) -> Vec<PathScore> {
     graph
         .node_indices()
+        .filter(|&target_node| {
+            let node = graph.node_weight(target_node).unwrap();
+            target.covers(node.start, node.end)
+        })
         .filter_map(|target_node| {
             let candidate = PathScore::new(
                 graph,
@@ -1,32 +1,40 @@
 use crate::kplib::{
-    brute_force_find_path, metrics::overlaps, traverse::get_one_to_one, GenotypeAnno, Haplotype,
-    KDParams, KdpVcf, PathScore, Ploidy,
+    add_partial_evidence, brute_force_find_path, metrics::overlaps, traverse::get_one_to_one,
+    AfModel, GenotypeAnno, Haplotype, KDParams, KdpVcf, PathScore, Ploidy, TrCatalog, VariantAnno,
 };
 use itertools::Itertools;
 use noodles_vcf::variant::RecordBuf;
 use petgraph::graph::{DiGraph, NodeIndex};
+use rust_htslib::faidx;
 
 #[derive(Debug)]
 pub struct VarNode {
     pub start: u64,
     pub end: u64,
     pub size: i64,
-    pub entry: Option<RecordBuf>,
     pub kfeat: Vec<f32>,
+    pub inv_span: u64, // Length of inverted sequence, zero if not an inversion
 }
 
 impl VarNode {
-    pub fn new(entry: RecordBuf, kmer: u8, maxhom: usize) -> Self {
+    /// One alternate allele of an entry. None when its kfeat can't be made
+    pub fn new(
+        entry: &RecordBuf,
+        alt_idx: usize,
+        reference: &faidx::Reader,
+        kmer: u8,
+        maxhom: usize,
+    ) -> Option<Self> {
         // Want to make a hash for these names for debugging, I think.
         let (start, end) = entry.boundaries();
-        let (kfeat, size) = entry.to_kfeat(kmer, maxhom);
-        Self {
+        let (kfeat, size) = entry.to_kfeat(alt_idx, reference, kmer, maxhom)?;
+        Some(Self {
             start,
             end,
             size,
-            entry: Some(entry),
             kfeat,
-        }
+            inv_span: entry.inv_span(alt_idx),
+        })
     }
 
     pub fn new_anchor(kmer: u8) -> Self {
@@ -34,8 +42,8 @@ impl VarNode {
             start: 0,
             end: 0,
             size: 0,
-            entry: None,
             kfeat: vec![0f32; 4_usize.pow(kmer.into())],
+            inv_span: 0,
         }
     }
 }
@@ -47,6 +55,10 @@ pub struct Variants {
     pub end: u64,
     pub node_indices: Vec<NodeIndex>,
     pub graph: DiGraph<VarNode, ()>,
+    // Each entry and the nodes of its alternate alleles
+    entries: Vec<(RecordBuf, Vec<Option<NodeIndex>>)>,
+    // Entries whose alleles couldn't be made into nodes
+    skipped: Vec<RecordBuf>,
 }
 
 /// Build a graph of all variants in a chunk.
@@ -54,8 +66,14 @@ pub struct Variants {
 /// Variants will have edges to every downstream variant that it does not overlap
 /// The graph has an upstream 'src' node that point to every variant node
 /// The graph has a dnstream 'snk' node that is pointed to by every variant node and 'src'
+/// Multi-allelic entries have a node per valid alternate allele. These overlap, so never share a path
 impl Variants {
-    pub fn new(mut variants: Vec<RecordBuf>, kmer: u8, maxhom: usize) -> Self {
+    pub fn new(
+        variants: Vec<RecordBuf>,
+        reference: &faidx::Reader,
+        kmer: u8,
+        maxhom: usize,
+    ) -> Self {
         if variants.is_empty() {
             panic!("Cannot create a graph from no variants");
         }
@@ -66,12 +84,40 @@ impl Variants {
         let mut node_indices = Vec::<NodeIndex<_>>::with_capacity(variants.len() + 2);
         node_indices.push(graph.add_node(VarNode::new_anchor(kmer)));
 
-        node_indices.append(
-            &mut variants
-                .drain(..) // drain lets us move the entry without a clone
-                .map(|entry| graph.add_node(VarNode::new(entry, kmer, maxhom)))
-                .collect(),
-        );
+        let mut entries = Vec::with_capacity(variants.len());
+        let mut skipped = vec![];
+        for entry in variants {
+            // Alleles which can't be genotyped (e.g. '*') have no node
+            let nodes: Option<Vec<Option<VarNode>>> = (0..entry.get_alts().len())
+                .map(|alt_idx| {
+                    if entry.valid_alt(alt_idx) {
+                        VarNode::new(&entry, alt_idx, reference, kmer, maxhom).map(Some)
+                    } else {
+                        Some(None)
+                    }
+                })
+                .collect();
+            let Some(nodes) = nodes else {
+                warn!(
+                    "Unable to fetch reference of symbolic allele at {}:{}. Skipping",
+                    chrom,
+                    entry.variant_start().map_or(0, usize::from)
+                );
+                skipped.push(entry);
+                continue;
+            };
+            let alleles = nodes
+                .into_iter()
+                .map(|node| {
+                    node.map(|node| {
+                        let idx = graph.add_node(node);
+                        node_indices.push(idx);
+                        idx
+                    })
+                })
+                .collect();
+            entries.push((entry, alleles));
+        }
 
         node_indices.push(graph.add_node(VarNode::new_anchor(kmer)));
 
@@ -81,6 +127,8 @@ impl Variants {
             end,
             node_indices,
             graph,
+            entries,
+            skipped,
         }
     }
 
@@ -105,8 +153,31 @@ impl Variants {
         }
     }
 
-    /// Again, TR aware, we need to set the bounds for doing the pileup
-    /// to the TR boundaries.
+    /// Positions of the insertions in the graph
+    pub fn ins_breakpoints(&self) -> Vec<u64> {
+        self.node_indices
+            .iter()
+            .filter_map(|&idx| self.graph.node_weight(idx))
+            .filter(|node| node.size > 0)
+            .map(|node| node.start)
+            .collect()
+    }
+
+    /// Window of reads to pileup. Reads must span the variants plus --neighdist or, when the
+    /// variants are in a --tr-catalog repeat, the repeat plus --tr-flank
+    pub fn pileup_window(&self, tr_catalog: &TrCatalog, params: &KDParams) -> (u64, u64) {
+        match tr_catalog.span(&self.chrom, self.start, self.end) {
+            Some((tr_start, tr_end)) => (
+                tr_start.min(self.start).saturating_sub(params.tr_flank),
+                tr_end.max(self.end) + params.tr_flank,
+            ),
+            None => (
+                self.start.saturating_sub(params.neighdist),
+                self.end + params.neighdist,
+            ),
+        }
+    }
+
     fn get_region(entries: &[RecordBuf]) -> (String, u64, u64) {
         let chrom = entries[0].reference_sequence_name().to_string();
 
@@ -129,52 +200,73 @@ impl Variants {
                 .cloned()
                 .unwrap_or_else(PathScore::default);
             ret.coverage = Some(hap.coverage);
+            ret.span = hap.span;
+            ret.reads = hap.reads.clone();
             ret
         } else {
             let mut ret = brute_force_find_path(&self.graph, hap, params);
             ret.coverage = Some(hap.coverage);
+            ret.span = hap.span;
+            ret.reads = hap.reads.clone();
             ret
         }
     }
 
     /// Transform the graph back into annotated variants
-    /// Note that this will take the entries out of the graph's VarNodes
+    /// Each sample provides its paths and coverage over the neighborhood, the paths of its
+    /// partially spanning reads, and its `AfModel`
+    /// Note that this will take the entries out of the graph
     pub fn take_annotated(
         &mut self,
-        paths: &[PathScore],
-        coverage: u64,
+        samples: &[(Vec<PathScore>, u64, Vec<PathScore>)],
         ploidy: &Ploidy,
-    ) -> Vec<GenotypeAnno> {
-        self.node_indices
-            .iter_mut()
-            .filter_map(|var_idx| {
-                self.graph
-                    .node_weight_mut(*var_idx)
-                    .unwrap()
-                    .entry
-                    .take()
-                    .map(|entry| {
-                        GenotypeAnno::new(entry, var_idx, paths, coverage, ploidy, self.start)
+        models: &[AfModel],
+    ) -> Vec<VariantAnno> {
+        let neigh_group = self.start;
+        self.entries
+            .drain(..)
+            .map(|(entry, alleles)| {
+                let (start, end) = entry.boundaries();
+                let samples = samples
+                    .iter()
+                    .zip(models)
+                    .map(|((paths, coverage, partials), model)| {
+                        // Only the partial reads covering this entry are evidence for it
+                        let partials: Vec<&PathScore> = partials
+                            .iter()
+                            .filter(|p| p.span.is_some_and(|(s, e)| s <= start && end <= e))
+                            .collect();
+                        let (paths, coverage) =
+                            add_partial_evidence(&alleles, paths, *coverage, &partials, ploidy);
+                        GenotypeAnno::new(&alleles, &paths, coverage, ploidy, neigh_group, model)
                     })
+                    .collect();
+                VariantAnno { entry, samples }
             })
-            .collect::<Vec<GenotypeAnno>>()
+            .chain(
+                self.skipped
+                    .drain(..)
+                    .map(|entry| VariantAnno::skipped(entry, samples.len())),
+            )
+            .collect::<Vec<VariantAnno>>()
     }
 
     /// Transform the graph back into annotated variants
-    /// Note that this will clone the entries from the graph's VarNodes
-    pub fn __clone_annotated(&mut self, paths: &[PathScore], coverage: u64) -> Vec<GenotypeAnno> {
-        self.node_indices
+    /// Note that this will clone the entries from the graph
+    pub fn __clone_annotated(&mut self, paths: &[PathScore], coverage: u64) -> Vec<VariantAnno> {
+        self.entries
             .iter()
-            .filter_map(|&var_idx| {
-                self.graph
-                    .node_weight(var_idx)
-                    .unwrap()
-                    .entry
-                    .as_ref()
-                    .map(|entry| {
-                        GenotypeAnno::new(entry.clone(), &var_idx, paths, coverage, &Ploidy::Unset, self.start)
-                    })
+            .map(|(entry, alleles)| VariantAnno {
+                entry: entry.clone(),
+                samples: vec![GenotypeAnno::new(
+                    alleles,
+                    paths,
+                    coverage,
+                    &Ploidy::Unset,
+                    self.start,
+                    &AfModel::default(),
+                )],
             })
-            .collect::<Vec<GenotypeAnno>>()
+            .collect::<Vec<VariantAnno>>()
     }
 }
@@ -1,7 +1,6 @@
-use crate::kplib::{GenotypeAnno, KDParams, KdpVcf, Ploidy, Regions};
+use crate::kplib::{KDParams, KdpVcf, Regions, TrCatalog, VariantAnno};
 use crossbeam_channel::Sender;
 use noodles_vcf::{self as vcf, variant::RecordBuf};
-use petgraph::graph::NodeIndex;
 use std::io::BufRead;
 
 /// Takes a vcf and filtering parameters to create in iterable which will
@@ -10,6 +9,7 @@ pub struct VcfChunker<R: BufRead> {
     pub m_vcf: vcf::io::Reader<R>,
     pub m_header: vcf::Header,
     regions: Regions,
+    tr_catalog: TrCatalog,
     params: KDParams,
     // Variables for tracking chunks
     cur_chrom: String,
@@ -21,7 +21,9 @@ pub struct VcfChunker<R: BufRead> {
     pub chunk_count: u64,
     pub call_count: u64,
     pub skip_count: u64,
-    result_sender: Sender<Option<Vec<GenotypeAnno>>>,
+    result_sender: Sender<Option<Vec<VariantAnno>>>,
+    // Skipped entries still need a column per sample
+    n_samples: usize,
 }
 
 impl<R: BufRead> VcfChunker<R> {
@@ -29,13 +31,16 @@ impl<R: BufRead> VcfChunker<R> {
         m_vcf: vcf::io::Reader<R>,
         m_header: vcf::Header,
         regions: Regions,
+        tr_catalog: TrCatalog,
         params: KDParams,
-        result_sender: Sender<Option<Vec<GenotypeAnno>>>,
+        result_sender: Sender<Option<Vec<VariantAnno>>>,
+        n_samples: usize,
     ) -> Self {
         Self {
             m_vcf,
             m_header,
             regions,
+            tr_catalog,
             params,
             cur_chrom: String::new(),
             cur_end: 0,
@@ -44,6 +49,7 @@ impl<R: BufRead> VcfChunker<R> {
             call_count: 0,
             skip_count: 0,
             result_sender,
+            n_samples,
         }
     }
 
@@ -54,12 +60,11 @@ impl<R: BufRead> VcfChunker<R> {
             return false;
         }
 
-        if !entry.valid_alt() {
-            return false;
-        }
-
-        let size = entry.size() as u32;
-        if self.params.sizemin > size || self.params.sizemax < size {
+        // Multi-allelic entries need at least one valid allele within the size boundaries
+        if !(0..entry.get_alts().len()).any(|alt_idx| {
+            let size = entry.size(alt_idx) as u32;
+            entry.valid_alt(alt_idx) && self.params.sizemin <= size && size <= self.params.sizemax
+        }) {
             return false;
         }
 
@@ -89,8 +94,6 @@ impl<R: BufRead> VcfChunker<R> {
             return false;
         }
 
-        // Need to make sure its sequence resolved
-        // We'll make an exception for <DEL> in the future? (ref fetch, though..)
         true
     }
 
@@ -112,13 +115,9 @@ impl<R: BufRead> VcfChunker<R> {
                         return Some(entry);
                     } else {
                         self.skip_count += 1;
-                        let _ = self.result_sender.send(Some(vec![GenotypeAnno::new(
+                        let _ = self.result_sender.send(Some(vec![VariantAnno::skipped(
                             entry.clone(),
-                            &NodeIndex::new(0),
-                            &[],
-                            0,
-                            &Ploidy::Zero,
-                            0,
+                            self.n_samples,
                         )]));
                     }
                 }
@@ -128,14 +127,19 @@ impl<R: BufRead> VcfChunker<R> {
 
     /// Checks if this variant is within params.chunksize distance of last
     /// seen variant in this chunk
-    /// If we wanted to be TR aware, when checking new_chunk, we don't just look at
-    /// cur_end but also the TR catalog. We want to chunk all TR changes together
-    /// regardless of their distance.
+    /// Variants in a --tr-catalog repeat span the whole repeat so all of the
+    /// repeat's changes are chunked together regardless of their distance.
     fn entry_in_chunk(&mut self, entry: &RecordBuf) -> bool {
         let check_chrom = entry.reference_sequence_name().to_string();
         let new_chrom = !self.cur_chrom.is_empty() && check_chrom != self.cur_chrom;
 
         let (start, end) = entry.boundaries();
+        let (start, end) = self
+            .tr_catalog
+            .span(&check_chrom, start, end)
+            .map_or((start, end), |(tr_start, tr_end)| {
+                (start.min(tr_start), end.max(tr_end))
+            });
         let new_chunk = self.cur_end != 0 && self.cur_end + self.params.neighdist < start;
 
         self.cur_chrom = check_chrom;
@@ -1,7 +1,12 @@
-use crate::kplib::seq_to_kmer;
+use crate::kplib::{reverse_complement, seq_to_kmer};
 use noodles_vcf::{
-    variant::record::AlternateBases, variant::record::Filters, variant::RecordBuf, Header,
+    variant::record::AlternateBases,
+    variant::record::Filters,
+    variant::record_buf::info::field::{value::Array, Value},
+    variant::RecordBuf,
+    Header,
 };
+use rust_htslib::faidx;
 use std::str::FromStr;
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
@@ -29,20 +34,85 @@ impl FromStr for Svtype {
     }
 }
 
+impl Svtype {
+    /// Type of a symbolic alternate allele which can be built from the reference
+    pub fn from_symbolic(alt: &str) -> Option<Self> {
+        match alt {
+            "<DEL>" => Some(Svtype::Del),
+            _ if alt.starts_with("<DEL:") => Some(Svtype::Del),
+            "<DUP>" | "<DUP:TANDEM>" => Some(Svtype::Dup),
+            "<INV>" => Some(Svtype::Inv),
+            _ => None,
+        }
+    }
+}
+
+/// Alternate alleles are referred to by their index in `get_alts`
 pub trait KdpVcf {
-    fn to_kfeat(&self, kmer: u8, maxhom: usize) -> (Vec<f32>, i64);
+    fn to_kfeat(
+        &self,
+        alt_idx: usize,
+        reference: &faidx::Reader,
+        kmer: u8,
+        maxhom: usize,
+    ) -> Option<(Vec<f32>, i64)>;
     fn boundaries(&self) -> (u64, u64);
-    fn size(&self) -> u64;
+    fn sv_span(&self, alt_idx: usize) -> Option<u64>;
+    fn size(&self, alt_idx: usize) -> u64;
+    fn inv_span(&self, alt_idx: usize) -> u64;
     fn is_filtered(&self, header: &Header) -> bool;
-    fn valid_alt(&self) -> bool;
-    fn get_alt(&self) -> &str;
+    fn valid_alt(&self, alt_idx: usize) -> bool;
+    fn get_alts(&self) -> Vec<&str>;
 }
 
 impl KdpVcf for RecordBuf {
-    /// Convert variant sequence to Kfeat
-    fn to_kfeat(&self, kmer: u8, maxhom: usize) -> (Vec<f32>, i64) {
+    /// Convert an alternate allele's sequence to Kfeat
+    /// Symbolic alleles use the reference sequence they span:
+    /// deletions remove it, tandem duplications insert a copy, and inversions reverse complement it
+    /// None when a symbolic allele's reference sequence can't be fetched (e.g. it's beyond the contig)
+    fn to_kfeat(
+        &self,
+        alt_idx: usize,
+        reference: &faidx::Reader,
+        kmer: u8,
+        maxhom: usize,
+    ) -> Option<(Vec<f32>, i64)> {
+        let alt_seq = self.get_alts()[alt_idx];
+        if let Some(svtype) = Svtype::from_symbolic(alt_seq) {
+            let span = self.sv_span(alt_idx)?;
+            let (start, _) = self.boundaries();
+            // Missing contigs have a length of -1. fetch_seq can't be called for them
+            let contig_len = reference.fetch_seq_len(self.reference_sequence_name()) as i64;
+            if contig_len < 0 || start + span >= contig_len as u64 {
+                return None;
+            }
+            // Skip the anchor base
+            let span_seq = reference
+                .fetch_seq(
+                    self.reference_sequence_name(),
+                    (start + 1) as usize,
+                    (start + span) as usize,
+                )
+                .ok()?
+                .to_vec();
+
+            return Some(match svtype {
+                Svtype::Del => (seq_to_kmer(&span_seq, kmer, true, maxhom), -(span as i64)),
+                Svtype::Dup => (seq_to_kmer(&span_seq, kmer, false, maxhom), span as i64),
+                _ => {
+                    let m_ref = seq_to_kmer(&span_seq, kmer, false, maxhom);
+                    let m_inv = seq_to_kmer(&reverse_complement(&span_seq), kmer, false, maxhom);
+                    let m_ret = m_inv
+                        .iter()
+                        .zip(m_ref.iter())
+                        .map(|(&x, &y)| x - y)
+                        .collect();
+                    (m_ret, 0)
+                }
+            });
+        }
+
         let ref_seq = self.reference_bases();
-        let alt_seq = self.get_alt();
 
         let size = alt_seq.len() as i64 - ref_seq.len() as i64;
 
@@ -52,24 +122,57 @@ impl KdpVcf for RecordBuf {
         let m_ret: Vec<_> = m_alt
             .iter()
             .zip(m_ref.iter())
-            .map(|(&x, &y)| (x - y))
+            .map(|(&x, &y)| x - y)
             .collect();
 
-        (m_ret, size)
+        Some((m_ret, size))
     }
 
     /// start and end positions of an entry
-    /// Zero-based
+    /// Zero-based. Symbolic alleles end after the reference span they affect
     fn boundaries(&self) -> (u64, u64) {
         let start: u64 = u64::try_from(usize::from(self.variant_start().unwrap())).unwrap() - 1;
-        let end: u64 = start + self.reference_bases().len() as u64;
+        let mut end: u64 = start + self.reference_bases().len() as u64;
+        for (alt_idx, alt) in self.get_alts().iter().enumerate() {
+            if Svtype::from_symbolic(alt).is_some() {
+                if let Some(span) = self.sv_span(alt_idx) {
+                    end = end.max(start + 1 + span);
+                }
+            }
+        }
         (start, end)
     }
 
-    /// grab entry's length as difference between ref and alt lens. Assumes valid_alt()
-    fn size(&self) -> u64 {
+    /// Length of reference affected by a symbolic allele from INFO SVLEN or END
+    /// SVLEN arrays hold a value per allele. A single SVLEN is used for every allele
+    fn sv_span(&self, alt_idx: usize) -> Option<u64> {
+        let svlen = match self.info().get("SVLEN") {
+            Some(Some(Value::Integer(v))) => Some(*v),
+            Some(Some(Value::Array(Array::Integer(v)))) if v.len() == 1 => v[0],
+            Some(Some(Value::Array(Array::Integer(v)))) => v.get(alt_idx).copied().flatten(),
+            _ => None,
+        };
+        if let Some(svlen) = svlen.filter(|v| *v != 0) {
+            return Some(svlen.unsigned_abs() as u64);
+        }
+
+        let pos = usize::from(self.variant_start()?) as u64;
+        match self.info().get("END") {
+            Some(Some(Value::Integer(end))) if (*end as u64) > pos => Some(*end as u64 - pos),
+            _ => None,
+        }
+    }
+
+    /// grab an alternate allele's length as difference between ref and alt lens. Assumes valid_alt()
+    /// Symbolic alleles' length is their span
+    fn size(&self, alt_idx: usize) -> u64 {
+        let alt_seq = self.get_alts()[alt_idx];
+        if Svtype::from_symbolic(alt_seq).is_some() {
+            return self.sv_span(alt_idx).unwrap_or(0);
+        }
+
         let r_len: u64 = self.reference_bases().len() as u64;
-        let a_len: u64 = self.get_alt().len() as u64;
+        let a_len: u64 = alt_seq.len() as u64;
 
         if r_len == a_len {
             if r_len == 1 {
@@ -82,6 +185,25 @@ impl KdpVcf for RecordBuf {
         r_len.abs_diff(a_len)
     }
 
+    /// Length of reference an alternate allele inverts. Zero for alleles which aren't inversions
+    /// Sequence-resolved inversions have an alt that's the reverse complement of the ref after the anchor base
+    fn inv_span(&self, alt_idx: usize) -> u64 {
+        let alt_seq = self.get_alts()[alt_idx];
+        if Svtype::from_symbolic(alt_seq) == Some(Svtype::Inv) {
+            return self.sv_span(alt_idx).unwrap_or(0);
+        }
+
+        let ref_seq = self.reference_bases().as_bytes();
+        let alt_seq = alt_seq.as_bytes();
+        if ref_seq.len() > 1
+            && ref_seq.len() == alt_seq.len()
+            && alt_seq[1..].eq_ignore_ascii_case(&reverse_complement(&ref_seq[1..]))
+        {
+            return ref_seq.len() as u64 - 1;
+        }
+        0
+    }
+
     /// checks if an entry's FILTER is '.' or PASS, true if it is filtered
     fn is_filtered(&self, header: &Header) -> bool {
         !(self.filters().is_empty()
@@ -91,19 +213,21 @@ impl KdpVcf for RecordBuf {
             }))
     }
 
-    /// Alternate sequence isn't '.' or '*' or bnd or symbolic
-    fn valid_alt(&self) -> bool {
-        let alt = self.get_alt();
-        alt != "." && alt != "*" && !alt.contains(':') && !alt.contains('<')
+    /// An alternate allele isn't '.' or '*' or bnd or an unresolvable symbolic allele
+    fn valid_alt(&self, alt_idx: usize) -> bool {
+        let alt_seq = self.get_alts()[alt_idx];
+        if Svtype::from_symbolic(alt_seq).is_some() {
+            return self.sv_span(alt_idx).is_some();
+        }
+        alt_seq != "." && alt_seq != "*" && !alt_seq.contains(':') && !alt_seq.contains('<')
     }
 
-    /// Returns the first alternate allele or a blank string with '.' if there isn't any
-    fn get_alt(&self) -> &str {
+    /// Returns the alternate alleles or a single '.' if there aren't any
+    fn get_alts(&self) -> Vec<&str> {
         let alts = self.alternate_bases();
         match alts.len() {
-            0 => ".",
-            1 => alts.iter().next().expect("I just checked").unwrap(),
-            _ => panic!("multi-allelic records not supported"),
+            0 => vec!["."],
+            _ => alts.iter().map(|alt| alt.unwrap_or(".")).collect(),
         }
     }
 }
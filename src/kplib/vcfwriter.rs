@@ -1,4 +1,4 @@
-use crate::kplib::{metrics::GTstate, GenotypeAnno};
+use crate::kplib::{metrics::GTstate, VariantAnno};
 use std::{
     collections::HashMap,
     fs::File,
@@ -25,26 +25,12 @@ pub struct VcfWriter {
 
 #[rustfmt::skip]
 impl VcfWriter {
-    /// Given a path and a header, setup a new output VCF
+    /// Given a path, a header, and the output sample names, setup a new output VCF
     pub fn new(
         out_path: &Option<PathBuf>,
         mut header: vcf::Header,
-        sample: &Option<String>,
+        samples: &[String],
     ) -> Self {
-        // Ensure sample is correctly set up
-        let sample_name = match sample {
-            Some(name) => name.clone(),
-            None => {
-                if header.sample_names().is_empty() {
-                    error!("--input contains no samples. --sample name must be provided");
-                    std::process::exit(1);
-                }
-                let samp_name = header.sample_names()[0].clone();
-                info!("Setting sample to {}", samp_name);
-                samp_name
-            }
-        };
-
         if !header.sample_names().is_empty() {
             warn!(
                 "Clearing {} sample columns in output",
@@ -52,7 +38,7 @@ impl VcfWriter {
             );
             header.sample_names_mut().clear();
         }
-        header.sample_names_mut().insert(sample_name);
+        header.sample_names_mut().extend(samples.iter().cloned());
 
         // Setup FORMAT header definitions
         let all_formats = header.formats_mut();
@@ -68,7 +54,9 @@ impl VcfWriter {
             ("NE", num1, format::Type::Integer, "Neighborhood phase set of entries"),
             ("DP", num1, format::Type::Integer, "Coverage over region"),
             ("AD", format::Number::ReferenceAlternateBases, format::Type::Integer, "Ref/Alt coverage"),
-            ("KS", format::Number::Unknown, format::Type::Integer, "Kanpig score"),
+            ("KS", format::Number::AlternateBases, format::Type::Integer, "Kanpig score of each allele's haplotype"),
+            ("PL", format::Number::Samples, format::Type::Integer, "Phred-scaled genotype likelihoods"),
+            ("GL", format::Number::Samples, format::Type::Float, "Log10 genotype likelihoods"),
         ];
         let new_fmts: Vec<String> = format_definitions.iter().map(|x| String::from(x.0)).collect();
 
@@ -98,10 +86,12 @@ impl VcfWriter {
         }
     }
 
-    pub fn anno_write(&mut self, mut annot: GenotypeAnno) {
-        *self.gtcounts.entry(annot.gt_state).or_insert(0) += 1;
-        *annot.entry.samples_mut() =
-            Samples::new(self.keys.clone(), vec![annot.make_fields()]);
+    pub fn anno_write(&mut self, mut annot: VariantAnno) {
+        for sample in &annot.samples {
+            *self.gtcounts.entry(sample.gt_state).or_insert(0) += 1;
+        }
+        let values = annot.samples.iter().map(|sample| sample.make_fields()).collect();
+        *annot.entry.samples_mut() = Samples::new(self.keys.clone(), values);
 
         self.buf.clear();
         let mut tmp = vcf::io::Writer::new(&mut self.buf);
@@ -3,10 +3,15 @@ extern crate log;
 
 mod kplib;
 pub use self::{
-    kplib::brute_force_find_path, kplib::build_region_tree, kplib::seq_to_kmer, kplib::BamParser,
-    kplib::BedParser, kplib::Cli, kplib::Commands, kplib::GTArgs, kplib::Haplotype,
-    kplib::IOParams, kplib::KDParams, kplib::KanpigParams, kplib::KdpVcf, kplib::PathScore,
-    kplib::Ploidy, kplib::PloidyRegions, kplib::PlupArgs, kplib::PlupParser, kplib::ReadParser,
-    kplib::ReadPileup, kplib::Regions, kplib::Svtype, kplib::VarNode, kplib::Variants,
-    kplib::VcfChunker, kplib::VcfWriter,
+    kplib::brute_force_find_path, kplib::build_region_tree, kplib::downsample_haplotypes,
+    kplib::haplotag_records, kplib::load_af_models, kplib::merge_intervals, kplib::parse_region,
+    kplib::read_plup_header, kplib::seq_to_kmer, kplib::write_af_models, kplib::AfFitter,
+    kplib::AfModel, kplib::BamParser, kplib::BedParser, kplib::Cli, kplib::Commands,
+    kplib::EvidenceWriter, kplib::GTArgs, kplib::HaplotagWriter, kplib::Haplotype, kplib::IOParams,
+    kplib::KDParams, kplib::KanpigParams, kplib::KdpVcf, kplib::MultiParser, kplib::PathScore,
+    kplib::Ploidy, kplib::PloidyRegions, kplib::PlupArgs, kplib::PlupFile, kplib::PlupMergeArgs,
+    kplib::PlupOutput, kplib::PlupParser, kplib::PlupStatsArgs, kplib::PlupViewArgs,
+    kplib::PlupWriter, kplib::ReadGroupFilter, kplib::ReadParser, kplib::ReadPileup,
+    kplib::Regions, kplib::Svtype, kplib::VarNode, kplib::Variants, kplib::VcfChunker,
+    kplib::VcfWriter, kplib::CONTIG_END,
 };
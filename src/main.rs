@@ -6,7 +6,13 @@ extern crate log;
 mod genotype_main;
 mod kplib;
 mod plup_main;
-use crate::{genotype_main::genotype_main, plup_main::plup_main};
+mod plup_merge_main;
+mod plup_stats_main;
+mod plup_view_main;
+use crate::{
+    genotype_main::genotype_main, plup_main::plup_main, plup_merge_main::plup_merge_main,
+    plup_stats_main::plup_stats_main, plup_view_main::plup_view_main,
+};
 use clap::Parser;
 use kplib::{Cli, Commands, KanpigParams};
 
@@ -40,5 +46,17 @@ fn main() {
             setup_logging(&args);
             plup_main(args)
         }
+        Commands::PlupMerge(args) => {
+            setup_logging(&args);
+            plup_merge_main(args)
+        }
+        Commands::PlupView(args) => {
+            setup_logging(&args);
+            plup_view_main(args)
+        }
+        Commands::PlupStats(args) => {
+            setup_logging(&args);
+            plup_stats_main(args)
+        }
     };
 }
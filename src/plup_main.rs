@@ -1,4 +1,6 @@
-use crate::kplib::{PlupArgs, ReadPileup};
+use crate::kplib::{
+    merge_intervals, parse_region, BedParser, PlupArgs, PlupOutput, ReadGroupFilter, ReadPileup,
+};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use indicatif::{ProgressBar, ProgressStyle};
 use rust_htslib::{
@@ -6,14 +8,15 @@ use rust_htslib::{
     bam::{self, IndexedReader, Read},
 };
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap},
     thread::{self, JoinHandle},
 };
 
-type InputType = Option<(String, u64, u64)>;
-type OutputType = Option<Vec<ReadPileup>>;
+// Chromosome, start, and end of a region, and the earliest start of reads it keeps
+type Region = (String, u64, u64, u64);
+// Regions are numbered so their results can be written in order
+type InputType = Option<(usize, Region)>;
+type OutputType = Option<(usize, Vec<ReadPileup>)>;
 
 /// Processes a specified region in a BAM file, filtering reads based on user-defined parameters and returning the results.
 ///
@@ -22,6 +25,8 @@ type OutputType = Option<Vec<ReadPileup>>;
 /// - `chrom`: A string representing the chromosome or reference sequence name to query.
 /// - `start`: The start position (inclusive) of the region to fetch, in 0-based coordinates.
 /// - `end`: The end position (exclusive) of the region to fetch, in 0-based coordinates.
+/// - `keep_start`: The earliest start of reads to keep. Before `start` for reads which overlap an interval's first region.
+/// - `read_groups`: The read groups selected by `--read-group` / `--rg-sample`.
 /// - `params`: A reference to a `PlupArgs` struct containing user-defined filtering criteria, including:
 ///     - `mapq`: Minimum mapping quality required for reads to be included.
 ///     - `mapflag`: Bitwise flags for filtering reads based on their SAM flag values.
@@ -29,7 +34,7 @@ type OutputType = Option<Vec<ReadPileup>>;
 ///     - `sizemax`: Maximum size threshold for reads to be included in the pileup.
 ///
 /// # Returns
-/// - `OutputType`: A collection of processed reads from the specified region that meet the filtering criteria, organized as defined in `OutputType`.
+/// - `Vec<ReadPileup>`: The processed reads starting in the region that meet the filtering criteria.
 ///
 /// # Panics
 /// This function panics if the `fetch` operation on the BAM reader fails, which can occur if the specified region is invalid or if there is an issue with the BAM file or its index.
@@ -47,7 +52,8 @@ type OutputType = Option<Vec<ReadPileup>>;
 /// let start = 100_000;
 /// let end = 200_000;
 ///
-/// let result = process_bam_region(&mut reader, &chrom, start, end, &params);
+/// let read_groups = ReadGroupFilter::default();
+/// let result = process_bam_region(&mut reader, &chrom, start, end, start, &read_groups, &params);
 /// // Process the result...
 /// ```
 fn process_bam_region(
@@ -55,8 +61,10 @@ fn process_bam_region(
     chrom: &String,
     start: u64,
     end: u64,
+    keep_start: u64,
+    read_groups: &ReadGroupFilter,
     params: &PlupArgs,
-) -> OutputType {
+) -> Vec<ReadPileup> {
     reader
         .fetch((chrom, start, end))
         .expect("Failed to fetch region");
@@ -68,68 +76,148 @@ fn process_bam_region(
         if !record.seq().is_empty()
             && record.mapq() >= params.mapq
             && (record.flags() & params.mapflag) == 0
-            && record.reference_start().unsigned_abs() >= start
+            && read_groups.keep(&record)
+            && record.reference_start().unsigned_abs() >= keep_start
             && record.reference_start().unsigned_abs() < end
         {
-            ret.push(ReadPileup::new(
+            let mut read = ReadPileup::new(
                 chrom.clone(),
                 &record,
                 params.sizemin,
                 params.sizemax,
-            ));
+                params.merge_gap,
+                params.hp_tag.as_bytes(),
+                params.ps_tag.as_bytes(),
+            );
+            if params.splits {
+                read.add_splits(&record, params.sizemin, params.sizemax, params.mapq);
+            }
+            if params.clips {
+                read.add_clips(&record, params.sizemin, params.sizemax);
+            }
+            if !params.quals {
+                read.pileups.iter_mut().for_each(|p| p.quals = None);
+            }
+            if !params.read_info {
+                read.clear_info();
+            }
+            ret.push(read);
         }
     }
-    Some(ret)
+    ret
 }
 
-/// Splits the reference sequences in a BAM file into regions of a specified size.
+/// Intervals of the BAM's contigs to index. Without --bed or --region, every contig is indexed whole.
+/// Otherwise, each contig's requested intervals are clipped to its length and merged.
 ///
 /// # Parameters
-/// - `bam_path`: A reference to a `PathBuf` pointing to the BAM file. The BAM file must be indexed.
-/// - `chunk_size`: The size of each region, in base pairs. The last region for a reference sequence may be smaller if the reference length is not a multiple of `chunk_size`.
+/// - `args`: The `PlupArgs` holding `--bed` and `--region`.
+/// - `header`: The header of the BAM file, which provides the contigs' names, order, and lengths.
 ///
 /// # Returns
-/// - `Vec<(String, u64, u64)>`: A vector of tuples where each tuple contains:
+/// - `Vec<(String, u64, u64)>`: The chromosome, start, and end of each interval, in 0-based, half-open coordinates,
+///   in the order of the BAM's contigs.
+fn bam_intervals(args: &PlupArgs, header: &bam::HeaderView) -> Vec<(String, u64, u64)> {
+    let restricted = args.bed.is_some() || !args.region.is_empty();
+    let mut requested: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    for region in args.region.iter() {
+        let (chrom, start, end) = parse_region(region).expect("--region is malformed");
+        requested.entry(chrom).or_default().push((start, end));
+    }
+    if let Some(bed) = &args.bed {
+        for entry in BedParser::new(bed).parse() {
+            requested
+                .entry(entry.chrom)
+                .or_default()
+                .push((entry.start, entry.end));
+        }
+    }
+
+    let mut ret = vec![];
+    for tid in 0..header.target_count() {
+        let chrom =
+            String::from_utf8(header.tid2name(tid).to_vec()).expect("Invalid UTF-8 in target name");
+        let length = header.target_len(tid).expect("Failed to get target length");
+        let intervals = match requested.remove(&chrom) {
+            Some(intervals) => intervals,
+            None if !restricted => vec![(0, length)],
+            None => continue,
+        };
+        let clipped = intervals
+            .into_iter()
+            .filter(|(start, _)| *start < length)
+            .map(|(start, end)| (start, end.min(length)))
+            .collect();
+        ret.extend(
+            merge_intervals(clipped)
+                .into_iter()
+                .map(|(start, end)| (chrom.clone(), start, end)),
+        );
+    }
+
+    for chrom in requested.keys() {
+        warn!("{} is not a contig of --bam", chrom);
+    }
+    ret
+}
+
+/// Splits intervals into regions of a specified size.
+///
+/// # Parameters
+/// - `intervals`: The chromosome, start, and end of each interval, as made by `bam_intervals`.
+/// - `chunk_size`: The size of each region, in base pairs. The last region of an interval may be smaller if its length is not a multiple of `chunk_size`.
+///
+/// # Returns
+/// - `Vec<Region>`: A vector of tuples where each tuple contains:
 ///   - The name of the reference sequence (`String`).
 ///   - The start position (inclusive) of the region (`u64`), in 0-based coordinates.
 ///   - The end position (exclusive) of the region (`u64`), in 0-based coordinates.
-///
-/// # Panics
-/// - This function panics if the BAM file cannot be opened or if its header contains invalid UTF-8.
-/// - Panics if any reference sequence's length cannot be determined.
+///   - The earliest start of reads to keep (`u64`). An interval's first region also keeps reads starting after
+///     the previous interval of the chromosome, so reads overlapping the interval's start are indexed once.
 ///
 /// # Example
 /// ```rust
-/// use std::path::PathBuf;
-///
-/// let bam_path = PathBuf::from("example.bam");
+/// let intervals = vec![(String::from("chr1"), 0, 3_000_000)];
 /// let chunk_size = 1_000_000; // Split regions into 1 Mb chunks
 ///
-/// let regions = split_into_regions(&bam_path, chunk_size);
-/// for (chrom, start, end) in regions {
+/// let regions = split_into_regions(&intervals, chunk_size);
+/// for (chrom, start, end, _) in regions {
 ///     println!("{}:{}-{}", chrom, start, end);
 /// }
 /// ```
-fn split_into_regions(bam_path: &PathBuf, chunk_size: usize) -> Vec<(String, u64, u64)> {
-    let reader = IndexedReader::from_path(bam_path).expect("Failed to open BAM file");
-    let header = reader.header().to_owned();
-
-    (0..header.target_count())
-        .flat_map(|tid| {
-            let target_name = String::from_utf8(header.tid2name(tid).to_vec())
-                .expect("Invalid UTF-8 in target name");
-            let target_len = header.target_len(tid).expect("Failed to get target length") as usize;
-
-            (0..target_len).step_by(chunk_size).map(move |start| {
-                let end = usize::min(start + chunk_size, target_len);
-                (target_name.clone(), start as u64, end as u64)
-            })
-        })
-        .collect()
+fn split_into_regions(intervals: &[(String, u64, u64)], chunk_size: u64) -> Vec<Region> {
+    let mut ret = vec![];
+    let mut prev_chrom = "";
+    let mut prev_end = 0;
+    for (chrom, start, end) in intervals {
+        if chrom != prev_chrom {
+            prev_chrom = chrom;
+            prev_end = 0;
+        }
+        for chunk_start in (*start..*end).step_by(chunk_size as usize) {
+            let keep_start = if chunk_start == *start {
+                prev_end
+            } else {
+                chunk_start
+            };
+            let chunk_end = u64::min(chunk_start + chunk_size, *end);
+            ret.push((chrom.clone(), chunk_start, chunk_end, keep_start));
+        }
+        prev_end = *end;
+    }
+    ret
 }
 
-pub fn plup_main(args: PlupArgs) {
-    let regions = split_into_regions(&args.bam, (args.chunk_size as usize) * 1000000);
+pub fn plup_main(mut args: PlupArgs) {
+    let intervals = {
+        let bam = IndexedReader::from_path(&args.bam).expect("Failed to open BAM file");
+        bam_intervals(&args, bam.header())
+    };
+    if args.bed.is_some() || !args.region.is_empty() {
+        info!("indexing {} intervals", intervals.len());
+        args.intervals = Some(intervals.clone());
+    }
+    let regions = split_into_regions(&intervals, args.chunk_size * 1000000);
     let num_regions = regions.len() as u64;
     info!("{} regions to process", num_regions);
 
@@ -144,36 +232,42 @@ pub fn plup_main(args: PlupArgs) {
         )
         .unwrap()
         .progress_chars("・🐷🥫");
+        let m_regions: Vec<String> = regions.iter().map(|(chrom, ..)| chrom.clone()).collect();
         thread::spawn(move || {
-            let mut writer: Box<dyn Write> = match m_args.output {
-                Some(ref path) => {
-                    let m_page = page_size::get() * 1000;
-                    let file = File::create(path).expect("Error Creating Output File");
-                    Box::new(BufWriter::with_capacity(m_page, file))
-                }
-                None => Box::new(BufWriter::new(std::io::stdout())),
-            };
-            // Header
-            let serialized = serde_json::to_string(&m_args).expect("Error writing header");
-            let prefixed = format!("# {}\n", serialized);
-            let _ = writer.write_all(prefixed.as_bytes());
+            let mut contigs = m_regions.clone();
+            contigs.dedup();
+            let mut writer = PlupOutput::new(&m_args, &contigs);
 
             let mut n_reads = 0;
             let pbar = ProgressBar::new(num_regions).with_style(sty);
             pbar.inc(0);
+            // Results arrive out of order. They're held until every region before them is done
+            let mut pending = BTreeMap::new();
+            let mut next_region = 0;
+            let mut chrom_reads: Vec<ReadPileup> = vec![];
             loop {
                 match result_receiver.recv() {
                     Ok(None) | Err(_) => break,
-                    Ok(Some(readplups)) => {
-                        for read in readplups {
-                            writeln!(writer, "{}", read).expect("Error writing to output file");
-                            n_reads += 1;
+                    Ok(Some((idx, readplups))) => {
+                        pending.insert(idx, readplups);
+                        while let Some(readplups) = pending.remove(&next_region) {
+                            if next_region > 0
+                                && m_regions[next_region] != m_regions[next_region - 1]
+                            {
+                                // Split alignments can extend a read's start before its region's,
+                                // so the chromosome is sorted as a whole
+                                n_reads += writer.write_chrom(std::mem::take(&mut chrom_reads));
+                            }
+                            chrom_reads.extend(readplups);
+                            next_region += 1;
                         }
                         pbar.inc(1);
                     }
                 }
             }
+            n_reads += writer.write_chrom(chrom_reads);
             pbar.finish();
+            writer.finish(&m_args.output);
             info!("processed {} reads", n_reads);
         })
     };
@@ -190,13 +284,22 @@ pub fn plup_main(args: PlupArgs) {
                 if let Some(ref ref_name) = m_args.reference {
                     let _ = m_bam.set_reference(ref_name);
                 }
+                let read_groups =
+                    ReadGroupFilter::new(m_bam.header(), &m_args.read_group, &m_args.rg_sample);
                 loop {
                     match m_receiver.recv() {
                         Ok(None) | Err(_) => break,
-                        Ok(Some(chunk)) => {
-                            let _ = m_result_sender.send(process_bam_region(
-                                &mut m_bam, &chunk.0, chunk.1, chunk.2, &m_args,
-                            ));
+                        Ok(Some((idx, chunk))) => {
+                            let readplups = process_bam_region(
+                                &mut m_bam,
+                                &chunk.0,
+                                chunk.1,
+                                chunk.2,
+                                chunk.3,
+                                &read_groups,
+                                &m_args,
+                            );
+                            let _ = m_result_sender.send(Some((idx, readplups)));
                         }
                     }
                 }
@@ -205,7 +308,7 @@ pub fn plup_main(args: PlupArgs) {
         .collect();
 
     // Send items to worker threads
-    for i in regions {
+    for i in regions.into_iter().enumerate() {
         task_sender.send(Some(i)).unwrap();
     }
 
@@ -0,0 +1,68 @@
+use crate::kplib::{
+    merge_intervals, read_plup_header, PlupArgs, PlupFile, PlupMergeArgs, PlupOutput, CONTIG_END,
+};
+use indexmap::{IndexMap, IndexSet};
+
+/// Union of the inputs' intervals. None when any input holds whole contigs
+fn merged_intervals(headers: &[PlupArgs]) -> Option<Vec<(String, u64, u64)>> {
+    let mut intervals: IndexMap<String, Vec<(u64, u64)>> = IndexMap::new();
+    for header in headers {
+        for (chrom, start, end) in header.intervals.as_ref()? {
+            intervals
+                .entry(chrom.clone())
+                .or_default()
+                .push((*start, *end));
+        }
+    }
+    Some(
+        intervals
+            .into_iter()
+            .flat_map(|(chrom, ivs)| {
+                merge_intervals(ivs)
+                    .into_iter()
+                    .map(move |(start, end)| (chrom.clone(), start, end))
+            })
+            .collect(),
+    )
+}
+
+pub fn plup_merge_main(args: PlupMergeArgs) {
+    let headers: Vec<_> = args
+        .inputs
+        .iter()
+        .map(|path| read_plup_header(path).expect("Failed to parse plup.gz header"))
+        .collect();
+    // Parameters were checked for compatibility. Reads keep the qualities and info they have
+    let mut header = headers[0].clone();
+    header.quals = headers.iter().any(|h| h.quals);
+    header.read_info = headers.iter().any(|h| h.read_info);
+    header.intervals = merged_intervals(&headers);
+    header.output.clone_from(&args.output);
+    header.binary = args.binary;
+
+    let mut inputs: Vec<PlupFile> = args.inputs.iter().map(|p| PlupFile::new(p)).collect();
+    let contigs: Vec<String> = inputs
+        .iter()
+        .flat_map(|input| input.contigs())
+        .collect::<IndexSet<String>>()
+        .into_iter()
+        .collect();
+    info!(
+        "merging {} plups over {} contigs",
+        inputs.len(),
+        contigs.len()
+    );
+
+    let mut writer = PlupOutput::new(&header, &contigs);
+    let mut n_reads = 0;
+    for chrom in contigs.iter() {
+        let reads = inputs
+            .iter_mut()
+            .flat_map(|input| input.fetch(chrom, 0, CONTIG_END, 0, u32::MAX))
+            .collect();
+        n_reads += writer.write_chrom(reads);
+    }
+    writer.finish(&args.output);
+    info!("merged {} reads", n_reads);
+    info!("finished");
+}
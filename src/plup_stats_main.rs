@@ -0,0 +1,258 @@
+use crate::kplib::{PlupFile, PlupStatsArgs, Svtype, CONTIG_END};
+use rust_htslib::faidx;
+use serde::Serialize;
+use std::{fs::File, io::BufWriter};
+
+/// Lower bounds of the pileup size histogram's bins
+const SIZE_BINS: [u64; 10] = [0, 50, 100, 200, 300, 500, 1000, 2500, 5000, 10000];
+/// Pileup types in the size histogram
+const SVTYPES: [Svtype; 3] = [Svtype::Del, Svtype::Ins, Svtype::Inv];
+
+/// Distribution of reads' reference span
+#[derive(Serialize, Default)]
+struct LengthSummary {
+    min: u64,
+    p25: u64,
+    median: u64,
+    p75: u64,
+    max: u64,
+    mean: f64,
+    n50: u64,
+}
+
+impl LengthSummary {
+    fn new(mut lengths: Vec<u64>) -> Self {
+        if lengths.is_empty() {
+            return Self::default();
+        }
+        lengths.sort_unstable();
+        let quantile = |q: f64| lengths[((lengths.len() - 1) as f64 * q).round() as usize];
+        let total: u64 = lengths.iter().sum();
+        // Reads at least this long hold half of the bases
+        let mut cumulative = 0;
+        let n50 = lengths
+            .iter()
+            .rev()
+            .find(|length| {
+                cumulative += **length;
+                cumulative * 2 >= total
+            })
+            .copied()
+            .unwrap_or_default();
+
+        Self {
+            min: lengths[0],
+            p25: quantile(0.25),
+            median: quantile(0.5),
+            p75: quantile(0.75),
+            max: lengths[lengths.len() - 1],
+            mean: total as f64 / lengths.len() as f64,
+            n50,
+        }
+    }
+}
+
+/// Coverage of a contig. Plups don't hold contig lengths, so depth over the contig's length needs
+/// a --reference. Span depth is over the span from the first read's start to the last read's end
+#[derive(Serialize)]
+struct ContigStats {
+    name: String,
+    reads: u64,
+    bases: u64,
+    span: u64,
+    span_depth: f64,
+    length: Option<u64>,
+    depth: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct SizeBin {
+    min: u64,
+    max: Option<u64>, // Exclusive. None for the last bin
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct SizeHistogram {
+    svtype: String,
+    count: u64,
+    bins: Vec<SizeBin>,
+}
+
+#[derive(Serialize)]
+struct PlupStats {
+    reads: u64,
+    hp_fraction: f64,
+    ps_fraction: f64,
+    reverse_fraction: Option<f64>, // Of reads with info (plup --read-info)
+    read_lengths: LengthSummary,
+    contigs: Vec<ContigStats>,
+    pileups: Vec<SizeHistogram>,
+}
+
+fn fraction(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+fn scan_plup(plup: &mut PlupFile, reference: Option<&faidx::Reader>) -> PlupStats {
+    let mut lengths = vec![];
+    let mut contigs = vec![];
+    let (mut n_hp, mut n_ps) = (0, 0);
+    let (mut n_info, mut n_reverse) = (0, 0);
+    let mut sizes = [[0u64; SIZE_BINS.len()]; SVTYPES.len()];
+
+    for name in plup.contigs() {
+        // Reads are streamed so only their lengths are held
+        let (mut n_reads, mut first, mut last, mut bases) = (0, u64::MAX, 0, 0);
+        for read in plup.fetch_iter(&name, 0, CONTIG_END, 0, u32::MAX) {
+            n_reads += 1;
+            first = first.min(read.start);
+            last = last.max(read.end);
+            bases += read.end - read.start;
+            lengths.push(read.end - read.start);
+            n_hp += read.hp.is_some() as u64;
+            n_ps += read.ps.is_some() as u64;
+            if let Some(strand) = read.strand() {
+                n_info += 1;
+                n_reverse += (strand == '-') as u64;
+            }
+            for pileup in read.pileups.iter() {
+                let Some(kind) = SVTYPES.iter().position(|s| *s == pileup.indel) else {
+                    continue;
+                };
+                let size = pileup.size.unsigned_abs();
+                sizes[kind][SIZE_BINS.partition_point(|bin| *bin <= size) - 1] += 1;
+            }
+        }
+        if n_reads == 0 {
+            continue;
+        }
+        let span = last.saturating_sub(first);
+        // Missing contigs have a length of -1
+        let length = reference
+            .map(|fai| fai.fetch_seq_len(&name) as i64)
+            .filter(|len| *len >= 0)
+            .map(|len| len as u64);
+        if reference.is_some() && length.is_none() {
+            warn!("{} isn't in the --reference", name);
+        }
+        contigs.push(ContigStats {
+            name,
+            reads: n_reads,
+            bases,
+            span,
+            span_depth: fraction(bases, span),
+            length,
+            depth: length.map(|len| fraction(bases, len)),
+        });
+    }
+
+    let n_reads = lengths.len() as u64;
+    let pileups = SVTYPES
+        .iter()
+        .zip(sizes.iter())
+        .map(|(svtype, counts)| SizeHistogram {
+            svtype: format!("{:?}", svtype).to_uppercase(),
+            count: counts.iter().sum(),
+            bins: SIZE_BINS
+                .iter()
+                .enumerate()
+                .map(|(i, min)| SizeBin {
+                    min: *min,
+                    max: SIZE_BINS.get(i + 1).copied(),
+                    count: counts[i],
+                })
+                .collect(),
+        })
+        .collect();
+
+    PlupStats {
+        reads: n_reads,
+        hp_fraction: fraction(n_hp, n_reads),
+        ps_fraction: fraction(n_ps, n_reads),
+        reverse_fraction: (n_info > 0).then(|| fraction(n_reverse, n_info)),
+        read_lengths: LengthSummary::new(lengths),
+        contigs,
+        pileups,
+    }
+}
+
+fn print_table(stats: &PlupStats) {
+    println!("{:<12}{:>12}", "reads", stats.reads);
+    println!("{:<12}{:>11.2}%", "with HP", stats.hp_fraction * 100.0);
+    println!("{:<12}{:>11.2}%", "with PS", stats.ps_fraction * 100.0);
+    if let Some(reverse) = stats.reverse_fraction {
+        println!("{:<12}{:>11.2}%", "reverse", reverse * 100.0);
+    }
+
+    let lengths = &stats.read_lengths;
+    println!("\nread length");
+    for (name, value) in [
+        ("min", lengths.min),
+        ("p25", lengths.p25),
+        ("median", lengths.median),
+        ("p75", lengths.p75),
+        ("max", lengths.max),
+        ("n50", lengths.n50),
+    ] {
+        println!("{:<12}{:>12}", name, value);
+    }
+    println!("{:<12}{:>12.1}", "mean", lengths.mean);
+
+    println!(
+        "\n{:<12}{:>12}{:>16}{:>16}{:>12}{:>16}{:>10}",
+        "contig", "reads", "bases", "span", "span_depth", "length", "depth"
+    );
+    for contig in stats.contigs.iter() {
+        let length = contig.length.map_or(".".to_string(), |len| len.to_string());
+        let depth = contig
+            .depth
+            .map_or(".".to_string(), |depth| format!("{:.2}", depth));
+        println!(
+            "{:<12}{:>12}{:>16}{:>16}{:>12.2}{:>16}{:>10}",
+            contig.name, contig.reads, contig.bases, contig.span, contig.span_depth, length, depth
+        );
+    }
+
+    print!("\n{:<12}", "size");
+    for hist in stats.pileups.iter() {
+        print!("{:>12}", hist.svtype);
+    }
+    println!();
+    for (i, min) in SIZE_BINS.iter().enumerate() {
+        let label = match SIZE_BINS.get(i + 1) {
+            Some(max) => format!("{}-{}", min, max),
+            None => format!("{}+", min),
+        };
+        print!("{:<12}", label);
+        for hist in stats.pileups.iter() {
+            print!("{:>12}", hist.bins[i].count);
+        }
+        println!();
+    }
+    print!("{:<12}", "total");
+    for hist in stats.pileups.iter() {
+        print!("{:>12}", hist.count);
+    }
+    println!();
+}
+
+pub fn plup_stats_main(args: PlupStatsArgs) {
+    let mut plup = PlupFile::new(&args.input);
+    let reference = args
+        .reference
+        .as_ref()
+        .map(|path| faidx::Reader::from_path(path).expect("Unable to open --reference"));
+    let stats = scan_plup(&mut plup, reference.as_ref());
+    print_table(&stats);
+
+    if let Some(path) = &args.json {
+        let file = BufWriter::new(File::create(path).expect("Error Creating --json"));
+        serde_json::to_writer_pretty(file, &stats).expect("Unable to write --json output");
+    }
+    info!("finished");
+}
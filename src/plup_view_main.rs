@@ -0,0 +1,128 @@
+use crate::kplib::{
+    merge_intervals, parse_region, read_plup_header, BedParser, PlupFile, PlupOutput, PlupViewArgs,
+    CONTIG_END,
+};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+
+/// Regions to extract per contig, in the plup's contig order. A contig's regions are sorted and
+/// overlapping ones are merged. Without --region or --bed, every contig is extracted
+fn view_regions(args: &PlupViewArgs, contigs: &[String]) -> IndexMap<String, Vec<(u64, u64)>> {
+    let mut regions: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    if let Some(region) = &args.region {
+        let (chrom, start, end) = parse_region(region).expect("--region is malformed");
+        regions.insert(chrom, vec![(start, end)]);
+    } else if let Some(bed) = &args.bed {
+        for entry in BedParser::new(bed).parse() {
+            regions
+                .entry(entry.chrom)
+                .or_default()
+                .push((entry.start, entry.end));
+        }
+    } else {
+        return contigs
+            .iter()
+            .map(|chrom| (chrom.clone(), vec![(0, CONTIG_END)]))
+            .collect();
+    }
+
+    for chrom in regions.keys().filter(|chrom| !contigs.contains(chrom)) {
+        warn!("{} has no reads in --input", chrom);
+    }
+
+    contigs
+        .iter()
+        .filter_map(|chrom| Some((chrom.clone(), merge_intervals(regions.remove(chrom)?))))
+        .collect()
+}
+
+/// Overlap of two contigs' sorted, merged intervals
+fn intersect_intervals(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut ret = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start < end {
+            ret.push((start, end));
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    ret
+}
+
+/// Intervals the extracted plup holds. These are the extracted regions, limited to the input's
+/// own intervals when it was made with --bed/--region
+fn view_intervals(
+    regions: &IndexMap<String, Vec<(u64, u64)>>,
+    held: &Option<Vec<(String, u64, u64)>>,
+) -> Vec<(String, u64, u64)> {
+    let mut lookup: HashMap<&str, Vec<(u64, u64)>> = HashMap::new();
+    if let Some(held) = held {
+        for (chrom, start, end) in held {
+            lookup.entry(chrom).or_default().push((*start, *end));
+        }
+    }
+
+    regions
+        .iter()
+        .flat_map(|(chrom, intervals)| {
+            let kept = match held {
+                None => intervals.clone(),
+                Some(_) => intersect_intervals(
+                    intervals,
+                    lookup.get(chrom.as_str()).map_or(&[], |v| v.as_slice()),
+                ),
+            };
+            kept.into_iter()
+                .map(|(start, end)| (chrom.clone(), start, end))
+        })
+        .collect()
+}
+
+pub fn plup_view_main(args: PlupViewArgs) {
+    let mut header = read_plup_header(&args.input).expect("Failed to parse plup.gz header");
+    // Pileups outside the plup's own size boundaries were never kept
+    let sizemin = args.sizemin.unwrap_or(header.sizemin).max(header.sizemin);
+    let sizemax = args.sizemax.unwrap_or(header.sizemax).min(header.sizemax);
+    header.sizemin = sizemin;
+    header.sizemax = sizemax;
+    let mapq = args.mapq.unwrap_or(header.mapq).max(header.mapq);
+    let mapflag = args.mapflag.unwrap_or(0) | header.mapflag;
+    header.mapq = mapq;
+    header.mapflag = mapflag;
+    header.output.clone_from(&args.output);
+    header.binary = args.binary;
+
+    let mut input = PlupFile::new(&args.input);
+    let regions = view_regions(&args, &input.contigs());
+    let contigs: Vec<String> = regions.keys().cloned().collect();
+    if args.region.is_some() || args.bed.is_some() {
+        header.intervals = Some(view_intervals(&regions, &header.intervals));
+    }
+
+    let mut writer = PlupOutput::new(&header, &contigs);
+    let mut n_reads = 0;
+    for (chrom, intervals) in regions {
+        let mut prev_end = 0;
+        for (start, end) in intervals {
+            // Reads starting before the previous region's end were written with it
+            for read in input
+                .fetch(&chrom, start, end, sizemin, sizemax)
+                .into_iter()
+                .filter(|read| read.start >= prev_end && read.passes(mapq, mapflag))
+            {
+                writer.write(&read);
+                n_reads += 1;
+            }
+            prev_end = end;
+        }
+    }
+    writer.finish(&args.output);
+    info!("extracted {} reads", n_reads);
+    info!("finished");
+}